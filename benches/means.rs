@@ -0,0 +1,109 @@
+/*!
+How long a means-to-an-end session with a lot of prices in it takes to
+answer queries, using
+
+  * `scan`: the original `range_average()`, which walked the whole map from
+    the start for every query,
+  * `range`: `range_average()` as it is now, with `BTreeMap::range()`, and
+  * `tree`: a `Prices`.
+
+Run with `cargo bench --bench means`, optionally followed by the number of
+prices to insert and the number of queries to make (default 200000 and
+2000).
+*/
+use std::{
+    collections::BTreeMap,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use ph::means::{range_average, Prices};
+
+/// `range_average()` as it used to be.
+fn scan_average(map: &BTreeMap<i32, i32>, low: i32, high: i32) -> i32 {
+    if high < low { return 0; }
+
+    let mut tot: i64 = 0;
+    let mut n: i64 = 0;
+
+    for (&ts, &val) in map.iter() {
+        if ts < low {
+            continue;
+        } else if ts <= high {
+            tot += val as i64;
+            n += 1;
+        } else {
+            break;
+        }
+    }
+
+    if n == 0 { return 0; }
+    (tot / n) as i32
+}
+
+/// A fixed, boring pseudorandom sequence, so every run does the same work.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> i32 {
+        self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        (self.0 >> 32) as i32
+    }
+}
+
+fn report(name: &str, n: usize, elapsed: Duration) {
+    println!(
+        "{:>6}: {:>6} in {:>10.3?} ({:>10.1?} each)",
+        name, n, elapsed, elapsed / n.max(1) as u32
+    );
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1).filter(|a| !a.starts_with('-'));
+    let n_inserts: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(200_000);
+    let n_queries: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(2_000);
+
+    let mut rng = Lcg(2);
+    let inserts: Vec<(i32, i32)> = (0..n_inserts).map(|_| (rng.next(), rng.next())).collect();
+    let queries: Vec<(i32, i32)> = (0..n_queries)
+        .map(|_| {
+            let (a, b) = (rng.next(), rng.next());
+            (a.min(b), a.max(b))
+        })
+        .collect();
+
+    println!("{} inserts, {} queries", n_inserts, n_queries);
+
+    let start = Instant::now();
+    let mut map = BTreeMap::new();
+    for &(ts, price) in inserts.iter() {
+        map.insert(ts, price);
+    }
+    report("map", n_inserts, start.elapsed());
+
+    let start = Instant::now();
+    let mut prices = Prices::new();
+    for &(ts, price) in inserts.iter() {
+        prices.insert(ts, price).unwrap();
+    }
+    report("prices", n_inserts, start.elapsed());
+
+    let mut answers = Vec::with_capacity(n_queries);
+    let start = Instant::now();
+    for &(low, high) in queries.iter() {
+        answers.push(black_box(prices.average(low, high)));
+    }
+    report("tree", n_queries, start.elapsed());
+
+    let start = Instant::now();
+    for (&(low, high), &answer) in queries.iter().zip(answers.iter()) {
+        assert_eq!(black_box(range_average(&map, low, high)), answer);
+    }
+    report("range", n_queries, start.elapsed());
+
+    let start = Instant::now();
+    for (&(low, high), &answer) in queries.iter().zip(answers.iter()) {
+        assert_eq!(black_box(scan_average(&map, low, high)), answer);
+    }
+    report("scan", n_queries, start.elapsed());
+}
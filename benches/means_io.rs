@@ -0,0 +1,138 @@
+/*!
+How many messages a second a means-to-an-end server gets through when
+clients send them as fast as they can, using
+
+  * `single`: the original handler, which read one message and wrote one
+    response at a time, and
+  * `batched`: `means::serve()` as it is now, which reads whatever's
+    arrived and answers it all in one write.
+
+Each client pipelines its messages (nine inserts to every query) without
+waiting for answers, and checks it gets the right number back.
+
+Run with `cargo bench --bench means_io`, optionally followed by the number
+of messages each client sends and the number of clients (default 200000
+and 4).
+*/
+use std::time::{Duration, Instant};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use ph::{
+    codec::Encode,
+    config::Config,
+    means::{self, Insert, Msg, Prices, Query},
+    shutdown::Coordinator,
+};
+
+/// The original handler, stock messages only.
+async fn single(mut sock: TcpStream) {
+    let mut buff = [0u8; 9];
+    let mut prices = Prices::new();
+    while sock.read_exact(&mut buff).await.is_ok() {
+        match Msg::decode(&buff) {
+            Ok(Msg::I(m)) => { prices.insert(m.timestamp, m.price).unwrap(); },
+            Ok(Msg::Q(q)) => {
+                let mean = prices.average(q.begin, q.end);
+                if sock.write_all(&mean.to_be_bytes()).await.is_err() {
+                    return;
+                }
+            },
+            _ => return,
+        }
+    }
+}
+
+async fn serve_single(listener: TcpListener) {
+    while let Ok((sock, _)) = listener.accept().await {
+        tokio::spawn(single(sock));
+    }
+}
+
+/// A fixed, boring pseudorandom sequence, so every run does the same work.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> i32 {
+        self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        (self.0 >> 32) as i32
+    }
+}
+
+/// `n` messages, every tenth one a query, and how many queries there are.
+fn messages(n: usize, seed: u64) -> (Vec<u8>, usize) {
+    let mut rng = Lcg(seed);
+    let mut data = Vec::with_capacity(9 * n);
+    let mut n_queries = 0;
+    for i in 0..n {
+        let (a, b) = (rng.next(), rng.next());
+        let msg = if i % 10 == 9 {
+            n_queries += 1;
+            Msg::Q(Query { begin: a.min(b), end: a.max(b) })
+        } else {
+            Msg::I(Insert { timestamp: a, price: b })
+        };
+        msg.encode(&mut data);
+    }
+    (data, n_queries)
+}
+
+/// Send `data` while reading back the answers to its `n_queries` queries.
+async fn client(addr: std::net::SocketAddr, data: Vec<u8>, n_queries: usize) {
+    let sock = TcpStream::connect(addr).await.unwrap();
+    sock.set_nodelay(true).unwrap();
+    let (mut reader, mut writer) = sock.into_split();
+
+    let send = tokio::spawn(async move {
+        writer.write_all(&data).await.unwrap();
+        writer
+    });
+    let mut answers = vec![0u8; 4 * n_queries];
+    reader.read_exact(&mut answers).await.unwrap();
+    drop(send.await.unwrap());
+}
+
+async fn run(addr: std::net::SocketAddr, n_messages: usize, n_clients: usize) -> Duration {
+    let work: Vec<_> = (0..n_clients).map(|i| messages(n_messages, i as u64)).collect();
+    let start = Instant::now();
+    let clients: Vec<_> = work.into_iter()
+        .map(|(data, n_queries)| tokio::spawn(client(addr, data, n_queries)))
+        .collect();
+    for c in clients {
+        c.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, n: usize, elapsed: Duration) {
+    println!(
+        "{:>7}: {:>8} in {:>10.3?} ({:>10.0} messages/s)",
+        name, n, elapsed, n as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1).filter(|a| !a.starts_with('-'));
+    let n_messages: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(200_000);
+    let n_clients: usize = args.next().and_then(|a| a.parse().ok()).unwrap_or(4);
+    let total = n_messages * n_clients;
+
+    println!("{} clients, {} messages each", n_clients, n_messages);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let old = tokio::spawn(serve_single(listener));
+    report("single", total, run(addr, n_messages, n_clients).await);
+    old.abort();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let coordinator = Coordinator::new();
+    tokio::spawn(means::serve(listener, Default::default(), Config::default(), coordinator.handle()));
+    report("batched", total, run(addr, n_messages, n_clients).await);
+    coordinator.shutdown(Duration::from_secs(1)).await;
+}
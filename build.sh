@@ -2,10 +2,12 @@
 
 set -xeuo pipefail
 
+# Builds the all-in-one `ph` server unless told to build a particular bin.
+BIN=${1:-ph}
 TARGET=x86_64-unknown-linux-musl
-BINARY=target/$TARGET/release/$1
+BINARY=target/$TARGET/release/$BIN
 
-cargo build --target $TARGET --bin $1 --release
+cargo build --target $TARGET --bin $BIN --release
 
 strip $BINARY
 mv $BINARY ./
\ No newline at end of file
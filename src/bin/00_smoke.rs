@@ -1,69 +1,54 @@
 /*!
 Protohackers Problem 0: Smoke Test
 
-Implement the TCP Echo Service; be able to handle at least 5 simultaneous
-clients.
-*/
-use std::io::ErrorKind;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-};
+The server itself lives in [`ph::smoke`]. Run with `--help` for options;
+besides the common ones, this takes
+
+  * `--engine ENGINE`: how to echo; `basic` (the default), `copy` (large
+    buffers), or `splice` (Linux only)
+  * `--mode MODE`: what to echo; `raw` (the default), `line` (whole lines
+    only), `reverse` (whole lines, backwards) or `upper`
+  * `--delay SECS[-SECS]`: wait `SECS`, or a random time in the range,
+    before each write
+  * `--split`: break each response into randomly-sized writes
+  * `--seed N`: seed for `--delay` and `--split`
 
-static LOCAL_ADDR: &str = "0.0.0.0:12321";
-const BUFFSIZE: usize = 1024;
+The last four only work with the basic engine.
 
-/// We're not going to try for any error recovery at all. We just drop
-/// clients on the floor if there's a problem.
-async fn handle(mut sock: TcpStream) {
-    let mut buff = [0u8; BUFFSIZE];
+Each `--bind` can also be `tcp://ADDR`, `udp://ADDR`, `unix://PATH` or
+`unixgram://PATH`, to echo over something other than TCP.
 
-    if let Err(e) = sock.readable().await {
-        eprintln!("Error waiting for socket to become readable: {}", &e);
-        return;
-    }
+Once it's shut down, it logs how many connections it served, how many
+bytes it echoed, and how many datagrams it dropped because too many echoes
+were waiting on `--delay`.
+*/
+use std::sync::Arc;
+
+use ph::{
+    config::{self, Config},
+    shutdown::{self, Coordinator},
+    smoke::{self, Options, Sockets, Stats},
+};
 
-    loop {
-        match sock.read(&mut buff).await {
-            Ok(0) => { break; }
-            Ok(n) => {
-                println!("Read {} bytes", n);
-                if let Err(e) = sock.write_all(&buff[..n]).await {
-                    eprintln!("Error writing to socket: {}", &e);
-                    break;
-                }
-                println!("Finished writing.");
-            },
-            Err(e) => {
-                if e.kind() != ErrorKind::WouldBlock {
-                    eprintln!("Error reading from socket: {}", &e);
-                    break;
-                } else {
-                    continue;
-                }
-            }
-        }
-    }
-    println!("Dropping connection.");
-    if let Err(e) = sock.shutdown().await {
-        eprintln!("Error shutting down socket: {}", &e);
-    }
-}
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    env_logger::init();
+    let cfg = Config::load("Protohackers Problem 0: Smoke Test", smoke::OPTS);
+    let opts = Options::from_config(&cfg).unwrap_or_else(|e| config::bail(&e));
+    let sockets = Sockets::bind(&cfg.bind).await.unwrap_or_else(|e| config::bail(&e));
+    log::info!("Bound to {:?}", &sockets.endpoints());
 
- #[tokio::main(flavor = "current_thread")]
- async fn main() {
-    let listener = TcpListener::bind(LOCAL_ADDR).await.unwrap();
-    println!("Bound to {}", LOCAL_ADDR);
+    let stats = Arc::new(Stats::default());
+    let coordinator = Coordinator::new();
+    tokio::spawn(smoke::serve_with_stats(
+        sockets, opts, cfg.clone(), coordinator.handle(), stats.clone()
+    ));
+    shutdown::signal().await;
+    shutdown::report(&coordinator.shutdown(cfg.drain_timeout).await);
 
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr))  => {
-                println!("Accepted incoming from {:?}", &addr);
-                handle(socket).await;
-            },
-            Err(e) => {
-                println!("Error with incoming connection: {}", &e);
-            }
-        }
-    }
- }
\ No newline at end of file
+    let totals = stats.totals();
+    log::info!(
+        "Served {} connections: {} bytes in, {} bytes out, {} datagrams dropped.",
+        stats.connections(), totals.bytes_in, totals.bytes_out, totals.dropped
+    );
+}
\ No newline at end of file
@@ -1,153 +1,42 @@
 /*!
-Protohackers Problem 1: Tracking Prices
-
-Collect timestamped price messages from each client and supply averages
-over given ranges.
+Protohackers Problem 2: Means to an End
+
+The server itself lives in [`ph::means`]. Run with `--help` for options;
+besides the common ones, this takes
+
+  * `--extensions`: understand the extra message types described in
+    [`ph::means`] as well as the stock ones
+  * `--duplicates POLICY`: what to do when a client sends a second price
+    for the same timestamp; `reject` it (and disconnect), keep the `first`,
+    keep the `last` (the default), or keep `all` of them
+  * `--rounding MODE`: how to round means; `truncate` toward zero (the
+    default), `floor`, or to the `nearest` whole number
+  * `--history PATH`: let sessions that start with an asset tag share that
+    asset's prices, which are kept in an append-only log at `PATH`
+  * `--session-memory BYTES`, `--total-memory BYTES`: budgets for how much
+    memory one session's prices, and all sessions' prices together, may use
+  * `--over-budget ACTION`: what to do with an insert that would go over a
+    budget; `disconnect` the client (the default), `reject` the insert, or
+    `evict` the session's oldest prices to make room
 */
-use std::{
-    collections::BTreeMap,
-    io::ErrorKind,
-};
-
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+use ph::{
+    config::{self, Config},
+    means::{self, Options},
+    net::Listeners,
+    shutdown::{self, Coordinator},
 };
 
-const LOCAL_ADDR: &str = "0.0.0.0:12321";
-
-#[derive(Debug, Clone, Copy)]
-struct Insert {
-    timestamp: i32,
-    price: i32,
-}
-
-#[derive(Debug, Clone, Copy)]
-struct Query {
-    begin: i32,
-    end: i32,
-}
-
-#[derive(Debug, Clone, Copy)]
-enum Msg {
-    I(Insert),
-    Q(Query),
-}
-
-fn range_average(map: &BTreeMap<i32, i32>, low: i32, high: i32) -> i32 {
-    if high < low { return 0; }
-
-    let mut tot: i64 = 0;
-    let mut n: i64 = 0;
-
-    for (&ts, &val) in map.iter() {
-        if ts < low {
-            continue;
-        } else if ts <= high {
-            tot += val as i64;
-            n += 1 as i64;
-        } else {
-            break;
-        }
-    }
-
-    if n == 0 { return 0; }
-    (tot / n) as i32
-}
-
-impl Msg {
-    pub fn decode(data: &[u8; 9]) -> Result<Msg, String> {
-        let mut buff = [0u8; 4];
-        
-        buff.clone_from_slice(&data[1..5]);
-        let a = i32::from_be_bytes(buff);
-        let mut buff = [0u8; 4];
-        buff.clone_from_slice(&data[5..9]);
-        let b = i32::from_be_bytes(buff);
-
-        match data[0] {
-            b'I' => { Ok(
-                Msg::I(Insert{ timestamp: a, price: b })
-            ) },
-            b'Q' => { Ok(
-                Msg::Q(Query{ begin: a, end: b })
-            ) },
-            x => { Err(format!(
-                "unrecognized behavior: {} (expected {} or {})",
-                x, b'I', b'Q'
-            ))}
-        }
-    }
-}
-
-async fn handler(sock: &mut TcpStream) -> Result<(), String> {
-    let mut buff = [0u8; 9];
-    let mut prices: BTreeMap<i32, i32> = BTreeMap::new();
-
-    loop {
-        if let Err(e) = sock.read_exact(&mut buff).await {
-            if e.kind() == ErrorKind::UnexpectedEof {
-                return Ok(());
-            } else {
-                return Err(format!(
-                    "Error reading from socket: {}", &e
-                ));
-            }
-        }
-
-        match Msg::decode(&buff)? {
-            Msg::I(m) => {
-                prices.insert(m.timestamp, m.price);
-            },
-            Msg::Q(m) => {
-                let avg = range_average(&prices, m.begin, m.end);
-                let buff = avg.to_be_bytes();
-                sock.write_all(&buff).await.map_err(|e| format!(
-                    "Error writing response to socket: {}", &e
-                ))?;
-            },
-        }
-    }
-}
-
-async fn handler_wrapper(mut sock: TcpStream, client_n: usize) {
-    if let Err(e) = handler(&mut sock).await {
-        log::info!("Error handling client {}: {}", client_n, &e);
-    }
-    match sock.shutdown().await {
-        Ok(()) => {
-            log::info!("Disconnected from client {}.", client_n);
-        },
-        Err(e) => {
-            log::error!(
-                "Error shutting down socket from client {}: {}",
-                client_n, &e
-            );
-        }
-    }
-}
-
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     env_logger::init();
 
-    let listener = TcpListener::bind(LOCAL_ADDR).await.unwrap();
-    log::info!("Bound to {}", LOCAL_ADDR);
-
-    let mut client_n: usize = 0;
+    let cfg = Config::load("Protohackers Problem 2: Means to an End", means::OPTS);
+    let opts = Options::from_config(&cfg).unwrap_or_else(|e| config::bail(&e));
+    let listener = Listeners::bind(&cfg.bind).await.unwrap_or_else(|e| config::bail(&e));
+    log::info!("Bound to {:?}", &listener.local_addrs());
 
-    loop {
-        match listener.accept().await {
-            Ok((sock, addr)) => {
-                log::info!("Accepted client {} from {:?}", client_n, &addr);
-                tokio::spawn(async move {
-                    handler_wrapper(sock, client_n).await
-                });
-                client_n += 1;
-            },
-            Err(e) => {
-                log::error!("Error with incoming connection: {}", &e);
-            },
-        }
-    }
+    let coordinator = Coordinator::new();
+    tokio::spawn(means::serve(listener, opts, cfg.clone(), coordinator.handle()));
+    shutdown::signal().await;
+    shutdown::report(&coordinator.shutdown(cfg.drain_timeout).await);
 }
\ No newline at end of file
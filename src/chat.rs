@@ -0,0 +1,464 @@
+/*!
+Protohackers Problem 3: Budget Chat
+
+Implement the [Budget Chat protocol](https://protohackers.com/problem/3).
+
+# Commands
+
+With `--commands`, a line starting with `/` is a command rather than
+something to say:
+
+| Command          | Effect                                                     |
+|------------------|------------------------------------------------------------|
+| `/msg NAME TEXT` | send `TEXT` to whoever is called `NAME`, and nobody else |
+| `/nick NAME`     | change your name, unless it's taken, telling everyone else |
+| `/who`           | list who else is here, as when you joined                |
+| `/me ACTION`     | tell everyone else `* YOURNAME ACTION`                   |
+
+Without it, those are ordinary messages, as the spec says.
+*/
+
+use std::collections::BTreeMap;
+
+use tokio::{
+    io::{
+        AsyncWriteExt, BufReader, AsyncBufReadExt,
+        ReadHalf, WriteHalf,
+    },
+    net::TcpStream,
+    sync::{broadcast, mpsc},
+};
+
+use crate::{
+    config::{Config, Opt},
+    net::Listeners,
+    shutdown::Shutdown,
+};
+
+/// The chat server's own options.
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "commands", arg: None, many: false,
+        help: "understand /msg, /nick, /who and /me commands",
+    },
+];
+
+/// Everything about how to serve that isn't common to all the servers.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Treat lines starting with `/` as [commands](self#commands).
+    pub commands: bool,
+}
+
+impl Options {
+    pub fn from_config(cfg: &Config) -> Result<Options, String> {
+        Ok(Options {
+            commands: cfg.get("commands")?.unwrap_or(false),
+        })
+    }
+}
+
+const LAGGED_TEXT: &[u8] = b"Your connection has lagged and dropped messages.\n";
+const WELCOME_TEXT: &[u8] = b"Welcome. Please enter the name you'd like to use.\n";
+const REJECT_TEXT: &[u8] = b"Your name must consist of one or more alphanumeric characters.\n";
+const CLOSING_TEXT: &[u8] = b"* The server is shutting down. Goodbye.\n";
+const COMMANDS_TEXT: &str = "* Commands are /msg NAME TEXT, /nick NAME, /who and /me ACTION.\n";
+
+/// Messages from the `Room` to `Client`s.
+#[derive(Clone, Debug)]
+pub enum Msg {
+    /// Deliver to every user but `id`.
+    All{ id: usize, text: String },
+    /// Deliver to only user `id`.
+    One{ id: usize, text: String },
+}
+
+/// `Client` actions to report to the `Room`.
+#[derive(Clone, Debug)]
+pub enum Evt {
+    Text{ id: usize, text: String },
+    Leave(usize),
+    Arrive{ id: usize, name: String },
+}
+
+/// The chat room itself, which tracks who's present and relays their
+/// messages.
+pub struct Room {
+    users: BTreeMap<usize, String>,
+    suck: mpsc::Receiver<Evt>,
+    blow: broadcast::Sender<Msg>,
+    commands: bool,
+}
+
+impl Room {
+    pub fn new(
+        evt_chan: mpsc::Receiver<Evt>,
+        bcast_chan: broadcast::Sender<Msg>,
+    ) -> Self {
+        Self {
+            users: BTreeMap::new(),
+            suck: evt_chan,
+            blow: bcast_chan,
+            commands: false,
+        }
+    }
+
+    /// Treat lines starting with `/` as [commands](self#commands).
+    pub fn commands(mut self, enabled: bool) -> Self {
+        self.commands = enabled;
+        self
+    }
+
+    /// Generate a message listing all the current occupants but `id`.
+    fn name_list(&self, id: usize) -> String {
+        let names: Vec<&str> = self.users.iter()
+            .filter(|(&other, _)| other != id)
+            .map(|(_, name)| name.as_str())
+            .collect();
+        
+        format!("* Also here: {}\n", &names.join(", "))
+    }
+
+    /// Act on the command `line` (without the leading `/`) from user `id`.
+    fn command(&mut self, id: usize, line: &str) {
+        let (cmd, arg) = line.trim_end().split_once(' ').unwrap_or((line.trim_end(), ""));
+        let name = self.users.get(&id).unwrap().clone();
+        let reply = |text: String| Msg::One { id, text };
+
+        let msgs = match cmd {
+            "msg" => match arg.split_once(' ') {
+                Some((to, text)) => {
+                    let text = format!("[{} -> {}] {}\n", &name, to, text);
+                    let ids: Vec<usize> = self.users.iter()
+                        .filter(|(_, other)| *other == to)
+                        .map(|(&other, _)| other)
+                        .collect();
+                    if ids.is_empty() {
+                        vec![reply(format!("* There's nobody here called {}.\n", to))]
+                    } else {
+                        ids.into_iter().map(|id| Msg::One { id, text: text.clone() }).collect()
+                    }
+                },
+                None => vec![reply(COMMANDS_TEXT.into())],
+            },
+            "nick" => {
+                if self.users.values().any(|other| other == arg) {
+                    vec![reply(format!("* The name {} is taken.\n", arg))]
+                } else if Client::name_ok(arg) {
+                    self.users.insert(id, arg.to_string());
+                    vec![
+                        Msg::All { id, text: format!("* {} is now known as {}.\n", &name, arg) },
+                        reply(format!("* You are now known as {}.\n", arg)),
+                    ]
+                } else {
+                    vec![reply(String::from_utf8_lossy(REJECT_TEXT).into())]
+                }
+            },
+            "who" => vec![reply(self.name_list(id))],
+            "me" => vec![Msg::All { id, text: format!("* {} {}\n", &name, arg) }],
+            _ => vec![reply(COMMANDS_TEXT.into())],
+        };
+        for msg in msgs {
+            // Whoever sent this may have left already, and if they were the
+            // only one here, there's nobody to receive it.
+            let _ = self.blow.send(msg);
+        }
+    }
+
+    /// Run the room.\
+    /// 
+    /// There is a lot of `.unwrap()`ping going on here, but
+    ///   * If `self.blow.send()` returns an error, we have serious problems,
+    ///     so let's just die.
+    ///   * The requested key should _always_ be in `self.users`; if it's
+    ///     not, something way weird has happened, so again, let's die.
+    pub async fn run(&mut self) {
+        while let Some(evt) = self.suck.recv().await {
+            log::info!("room: {:?}", &evt);
+            match evt {
+                Evt::Text { id, text } if self.commands && text.starts_with('/') => {
+                    self.command(id, &text[1..]);
+                },
+                Evt::Text { id, text } => {
+                    let name = self.users.get(&id).unwrap();
+                    let text = format!("[{}] {}", name, &text);
+                    self.blow.send(Msg::All{ id, text }).unwrap();
+                },
+                Evt::Arrive{ id, name } => {
+                    let msg = Msg::All {
+                        text: format!("* {} joins.\n", &name),
+                        id,
+                    };
+                    self.blow.send(msg).unwrap();
+
+                    let msg = Msg::One {
+                        text: self.name_list(id),
+                        id,
+                    };
+                    self.blow.send(msg).unwrap();
+                    self.users.insert(id, name);
+                },
+                Evt::Leave(id) => {
+                    let name = self.users.remove(&id).unwrap();
+                    let msg = Msg::All {
+                        text: format!("* {} leaves.\n", &name),
+                        id,
+                    };
+                    // If no one is left in the `Room`, this will return an
+                    // error, so we are satisfy the compiler here by
+                    // "handling" it.
+                    let _ = self.blow.send(msg);
+                },
+            }
+        }
+    }
+}
+
+/// Handles to a connected client's socket, internal buffer, and user id.
+pub struct Client {
+    id: usize,
+    suck: BufReader<ReadHalf<TcpStream>>,
+    blow: WriteHalf<TcpStream>,
+    buff: Vec<u8>,
+}
+
+/// Possible results of calling `Client::get_line()`.
+pub enum ClientResult {
+    Line(String),
+    Eof,
+    Err(String),
+}
+
+impl Client {
+    pub fn new(sock: TcpStream, id: usize) -> Client {
+        let (r, w) = tokio::io::split(sock);
+        Client {
+            id,
+            suck: BufReader::new(r),
+            blow: w,
+            buff: Vec::new(),
+        }
+    }
+
+    /// Ensure a new client's name conforms to the requirements: A nonzero number
+    /// of only alphanumeric characters.
+    fn name_ok(name: &str) -> bool {
+        if name.is_empty() { return false; }
+
+        for c in name.chars() {
+            if !c.is_alphanumeric() { return false; }
+        }
+
+        true
+    }
+
+    /// Attempt to read a single line of text from the socket.
+    pub async fn get_line(&mut self) -> ClientResult {
+        let res = self.suck.read_until(b'\n', &mut self.buff).await;
+        log::debug!("Client {} read_line() result: {:?}", self.id, &res);
+        match res {
+            Ok(0) => ClientResult::Eof,
+            Ok(_) => {
+                let mut new_buff: Vec<u8> = Vec::new();
+                std::mem::swap(&mut self.buff, &mut new_buff);
+
+                // The spec says that all incoming text should be ASCII, but
+                // we're going to be defensive here anyway.
+                let mut line: String = match String::from_utf8(new_buff) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        log::warn!(
+                            "Client {} rec'd non-UTF-8 input; returning approximation.",
+                            self.id
+                        );
+                        // We need the `.into()` because this function
+                        // returns a `Cow`, and we want to be sure we have
+                        // a `String`.
+                        String::from_utf8_lossy(&e.into_bytes()).into()
+                    }
+                };
+
+                // This unwrapping is okay because we've read at least one
+                // byte into `self.buff`.
+                if *line.as_bytes().last().unwrap() != b'\n' {
+                    // This might happen if this is the last line from the
+                    // client. We'll add the newline because the function of
+                    // the rest of the program depends on it.
+                    line.push('\n');
+                }
+                log::debug!("Client {} read_line() returns {:?}", self.id, &line);
+                ClientResult::Line(line)
+            },
+            Err(e) => ClientResult::Err(format!("{}", &e)),
+        }
+    }
+
+    /// Attempt to write a message to the socket.
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), ()> {
+        log::trace!(
+            "Client {} attempting to write: {:?}",
+            self.id, String::from_utf8_lossy(chunk)
+        );
+        if let Err(e) = self.blow.write_all(chunk).await {
+            log::error!(
+                "Client {}: error writing to socket: {}", self.id, &e
+            );
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub async fn shutdown(self) {
+        let mut sock = self.suck.into_inner().unsplit(self.blow);
+        if let Err(e) = sock.shutdown().await {
+            log::error!("Client {}: error shutting down socket: {}", self.id, &e);
+        }
+        log::info!("Client {} disconnects.", self.id);
+    }
+
+    /// Interact with the client until it leaves or `shutdown`, when it gets
+    /// told the server is closing.
+    /// 
+    /// This should be run in its own async task.
+    pub async fn run(
+        mut self,
+        mut recv: broadcast::Receiver<Msg>,
+        send: mpsc::Sender<Evt>,
+        shutdown: Shutdown,
+    ) {
+        if self.write(WELCOME_TEXT).await.is_err() {
+           self.shutdown().await;
+           return;
+        }
+        
+        let res = tokio::select! {
+            res = self.get_line() => res,
+            _ = shutdown.wait() => {
+                let _ = self.write(CLOSING_TEXT).await;
+                self.shutdown().await;
+                return;
+            },
+        };
+        if let ClientResult::Line(name) = res {
+            let name = name.trim().to_string();
+            if !Client::name_ok(&name) {
+                log::info!("Client {} attempts bad name: {:?}", self.id, &name);
+                let _ = self.write(REJECT_TEXT).await;
+                self.shutdown().await;
+                return;
+            }
+
+            // Empty the channel, in case any messages leaked in prior to the
+            // join. This is kind of a hack, but I can't think of better way
+            // to do this that isn't unnecessarily labyrinthine.
+            while recv.try_recv().is_ok() { /* do bupkis */ }
+
+            let evt = Evt::Arrive{ id: self.id, name };
+            send.send(evt).await.unwrap();
+        } else {
+            log::error!(
+                "Error receiving a name message from Client {}.",
+                self.id
+            );
+            self.shutdown().await;
+            return;
+        }
+
+        loop {
+            tokio::select!{
+                res = self.get_line() => match res {
+                    ClientResult::Line(line) => {
+                        let evt = Evt::Text{ id: self.id, text: line };
+                        send.send(evt).await.unwrap();
+                    },
+                    ClientResult::Eof => { break; },
+                    ClientResult::Err(e) => {
+                        log::error!(
+                            "Error reading from client {} socket: {}",
+                            self.id, &e
+                        );
+                        break;
+                    }
+                },
+                res = recv.recv() => {
+                    log::info!("Client {}: {:?}", self.id, &res);
+                    match res {
+                        Ok(Msg::All{ id, text }) => {
+                            if id != self.id && self.write(text.as_bytes()).await.is_err() {
+                                break;
+                            }
+                        },
+                        Ok(Msg::One{ id, text }) => {
+                            if id == self.id && self.write(text.as_bytes()).await.is_err() {
+                                break;
+                            }
+                        },
+                        Err(broadcast::error::RecvError::Closed) => {
+                            log::error!("Broadcast channel closed.");
+                            break;
+                        },
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            log::warn!(
+                                "Client {} has dropped messages.",
+                                self.id
+                            );
+                            if self.write(LAGGED_TEXT).await.is_err() { break; }
+                        },
+                    }
+                },
+                _ = shutdown.wait() => {
+                    let _ = self.write(CLOSING_TEXT).await;
+                    break;
+                },
+            }
+        }
+
+        send.send(Evt::Leave(self.id)).await.unwrap();
+        self.shutdown().await;
+    }
+}
+
+/// Run a `Room`, and accept connections to it on `listener` until
+/// `shutdown`.
+///
+/// `cfg.channel_size` sets the capacity of both the channel from clients to
+/// the `Room` and the broadcast channel back; a client that falls that far
+/// behind starts missing messages.
+pub async fn serve(
+    listener: impl Into<Listeners>,
+    opts: Options,
+    cfg: Config,
+    shutdown: Shutdown,
+) {
+    let listener = listener.into().limit(cfg.max_connections);
+    let (evt_tx, evt_rx) = mpsc::channel(cfg.channel_size);
+    let (bcast_tx, _) = broadcast::channel(cfg.channel_size);
+    let mut room = Room::new(evt_rx, bcast_tx.clone()).commands(opts.commands);
+    tokio::spawn(async move { room.run().await; });
+
+    let mut client_n: usize = 0;
+    loop {
+        let res = tokio::select! {
+            res = listener.accept() => res,
+            _ = shutdown.wait() => { break; },
+        };
+        match res {
+            Ok((sock, addr, slot)) => {
+                log::info!("Rec'd connection {} from {:?}", client_n, &addr);
+                let tracked = shutdown.track(format!("chat client {} ({:?})", client_n, &addr));
+                let client = Client::new(sock, client_n);
+                client_n += 1;
+                let (bcast_tr, evt_tx) = (bcast_tx.subscribe(), evt_tx.clone());
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move { 
+                    client.run(bcast_tr, evt_tx, shutdown).await;
+                    drop((slot, tracked));
+                });
+            },
+            Err(e) => {
+                log::error!("Error with incoming connection: {}", &e);
+            }
+        }
+    }
+}
@@ -0,0 +1,438 @@
+/*!
+Encoding and decoding binary protocol messages.
+
+Most binary protocols are built from big-endian integers and
+length-prefixed strings, one after another. A type that can be written that
+way implements [`Encode`]; one that can be read back implements [`Decode`],
+by reading its fields from a [`Reader`] in order. Nothing is derived;
+writing the impls out by hand keeps the wire format where you can see it.
+
+```
+use ph::codec::{self, Decode, Encode, Error, Reader, Str8};
+
+struct Hello { id: u16, name: Str8 }
+
+impl Encode for Hello {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.id.encode(out);
+        self.name.encode(out);
+    }
+}
+
+impl Decode for Hello {
+    fn decode(r: &mut Reader) -> Result<Self, Error> {
+        Ok(Hello { id: r.read()?, name: r.read()? })
+    }
+}
+
+let bytes = codec::to_bytes(&Hello { id: 7, name: Str8("bob".into()) });
+assert_eq!(bytes, b"\x00\x07\x03bob");
+let hello: Hello = codec::decode_exact(&bytes).unwrap();
+assert_eq!(hello.name.0, "bob");
+```
+
+Messages read off a socket don't arrive one at a time; [`Frames`] buffers
+whatever has arrived and hands back each message once all of it is there.
+*/
+use std::{fmt, io, marker::PhantomData};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Why a value couldn't be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// There weren't enough bytes; there may be more on the way.
+    Incomplete,
+    /// The bytes don't make sense; more of them won't help.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Incomplete => write!(f, "incomplete message"),
+            Error::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(e: String) -> Self {
+        Error::Invalid(e)
+    }
+}
+
+impl From<Error> for String {
+    fn from(e: Error) -> Self {
+        e.to_string()
+    }
+}
+
+/// Something that can be written in a binary protocol.
+pub trait Encode {
+    /// Append `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+/// Something that can be read from a binary protocol.
+pub trait Decode: Sized {
+    /// Read one value from `r`.
+    fn decode(r: &mut Reader) -> Result<Self, Error>;
+}
+
+/// Reads values, one after another, from a slice of bytes.
+#[derive(Clone, Debug)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    /// How many bytes have been read so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The bytes that haven't been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    /// Read the next `n` bytes.
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let bytes = self.remaining().get(..n).ok_or(Error::Incomplete)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// Read the next `N` bytes into an array.
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Read a `T`. If that fails, nothing is read.
+    pub fn read<T: Decode>(&mut self) -> Result<T, Error> {
+        let start = self.pos;
+        T::decode(self).inspect_err(|_| self.pos = start)
+    }
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(r: &mut Reader) -> Result<Self, Error> {
+                Ok(<$t>::from_be_bytes(r.array()?))
+            }
+        }
+    )*};
+}
+
+// Always big-endian, which is what everybody means by network byte order.
+impl_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+macro_rules! impl_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: Encode),*> Encode for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode(&self, out: &mut Vec<u8>) {
+                let ($($name,)*) = self;
+                $($name.encode(out);)*
+            }
+        }
+
+        impl<$($name: Decode),*> Decode for ($($name,)*) {
+            fn decode(r: &mut Reader) -> Result<Self, Error> {
+                Ok(($(r.read::<$name>()?,)*))
+            }
+        }
+    };
+}
+
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+
+macro_rules! impl_str {
+    ($(#[$doc:meta])* $name:ident, $len:ty) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+        pub struct $name(pub String);
+
+        impl Encode for $name {
+            /// Panics if the string is too long for its length to fit.
+            fn encode(&self, out: &mut Vec<u8>) {
+                let len = <$len>::try_from(self.0.len()).unwrap_or_else(|_| panic!(
+                    "{}-byte string is too long for a {} length",
+                    self.0.len(), stringify!($len)
+                ));
+                len.encode(out);
+                out.extend_from_slice(self.0.as_bytes());
+            }
+        }
+
+        impl Decode for $name {
+            fn decode(r: &mut Reader) -> Result<Self, Error> {
+                let len: $len = r.read()?;
+                let bytes = r.take(len as usize)?;
+                match std::str::from_utf8(bytes) {
+                    Ok(s) => Ok($name(s.to_string())),
+                    Err(e) => Err(Error::Invalid(format!("invalid string: {}", &e))),
+                }
+            }
+        }
+    };
+}
+
+impl_str!(
+    /// A UTF-8 string, preceded by its length in bytes as a `u8`.
+    Str8, u8
+);
+impl_str!(
+    /// A UTF-8 string, preceded by its length in bytes as a `u16`.
+    Str16, u16
+);
+impl_str!(
+    /// A UTF-8 string, preceded by its length in bytes as a `u32`.
+    Str32, u32
+);
+
+/// Encode `value` on its own.
+pub fn to_bytes<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+/// Decode a `T` that takes up all of `data`.
+pub fn decode_exact<T: Decode>(data: &[u8]) -> Result<T, String> {
+    let mut r = Reader::new(data);
+    let value = r.read()?;
+    match r.remaining().len() {
+        0 => Ok(value),
+        n => Err(format!("{} bytes left over", n)),
+    }
+}
+
+/// Longest message a [`Frames`] will wait for, unless told otherwise.
+pub const MAX_FRAME: usize = 64 * 1024;
+
+/**
+Decodes a stream of `T`s from bytes that arrive in whatever pieces they
+like, keeping the start of any message that isn't all there yet until the
+rest of it arrives.
+
+Feed it with [`push`](Frames::push) or [`read_from`](Frames::read_from),
+then iterate to get every message that's complete. The iterator stops
+(for now) at the first incomplete one. After an error there's no telling
+where the next message starts, so the iterator returns it once and then
+nothing more; give up on the stream.
+
+A message longer than [`max_frame`](Frames::with_max_frame) bytes is an
+error, which it reports as soon as it's seen that many without reaching
+the end, so a peer can't make it buffer without limit by claiming some
+enormous length.
+*/
+#[derive(Debug)]
+pub struct Frames<T> {
+    buf: Vec<u8>,
+    /// Where the first message that hasn't been decoded starts.
+    start: usize,
+    max_frame: usize,
+    /// Set once the iterator has returned an error.
+    failed: bool,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for Frames<T> {
+    fn default() -> Self {
+        Frames {
+            buf: Vec::new(),
+            start: 0,
+            max_frame: MAX_FRAME,
+            failed: false,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T: Decode> Frames<T> {
+    /// Allow messages up to [`MAX_FRAME`] bytes.
+    pub fn new() -> Frames<T> {
+        Frames::default()
+    }
+
+    /// Allow messages up to `max_frame` bytes.
+    pub fn with_max_frame(max_frame: usize) -> Frames<T> {
+        Frames { max_frame, ..Frames::default() }
+    }
+
+    /// Make room at the end of the buffer by dropping what's been decoded.
+    fn compact(&mut self) {
+        self.buf.drain(..self.start);
+        self.start = 0;
+    }
+
+    /// Add some bytes to the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.compact();
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Read once from `reader`, up to at least `max` bytes, and return how
+    /// many were read; 0 means the stream has ended.
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        max: usize,
+    ) -> io::Result<usize> {
+        self.compact();
+        self.buf.reserve(max);
+        reader.read_buf(&mut self.buf).await
+    }
+
+    /// The bytes that haven't been decoded yet, which (unless there's been
+    /// an error) are the start of a message that isn't all there.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    /// Decode the next message, or return `None` if it isn't all there.
+    pub fn next_frame(&mut self) -> Result<Option<T>, String> {
+        let pending = self.pending();
+        // Anything that doesn't fit in `max_frame` bytes is too long.
+        let mut r = Reader::new(&pending[..pending.len().min(self.max_frame)]);
+        match r.read() {
+            Ok(value) => {
+                self.start += r.position();
+                Ok(Some(value))
+            },
+            Err(Error::Incomplete) if self.pending().len() > self.max_frame => Err(format!(
+                "message is longer than {} bytes", self.max_frame
+            )),
+            Err(Error::Incomplete) => Ok(None),
+            Err(Error::Invalid(e)) => Err(e),
+        }
+    }
+}
+
+impl<T: Decode> Iterator for Frames<T> {
+    type Item = Result<T, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let item = self.next_frame().transpose();
+        self.failed = matches!(item, Some(Err(_)));
+        item
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = (0x1234u16, -2i32, (u64::MAX, Str8("hi".into())));
+        let bytes = to_bytes(&value);
+        assert_eq!(bytes.len(), 2 + 4 + 8 + 1 + 2);
+        assert_eq!(&bytes[..6], &[0x12, 0x34, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(decode_exact::<(u16, i32, (u64, Str8))>(&bytes), Ok(value));
+
+        assert_eq!(to_bytes(&Str32("abc".into())), b"\0\0\0\x03abc");
+        assert_eq!(decode_exact::<Str16>(b"\0\0"), Ok(Str16(String::new())));
+        assert!(decode_exact::<Str8>(b"\x01\xff").is_err());
+        assert!(decode_exact::<u16>(b"\0\0\0").is_err());
+        assert!(decode_exact::<u32>(b"\0\0\0").is_err());
+    }
+
+    #[test]
+    fn failed_reads_read_nothing() {
+        let mut r = Reader::new(b"\x00\x01\x05ab");
+        assert_eq!(r.read::<(u16, Str8)>(), Err(Error::Incomplete));
+        assert_eq!(r.position(), 0);
+        assert_eq!(r.read::<u16>(), Ok(1));
+        assert_eq!(r.remaining(), b"\x05ab");
+    }
+
+    #[test]
+    fn partial_frames() {
+        let mut data = Vec::new();
+        let words = ["one", "", "three", "four"];
+        for (n, word) in words.iter().enumerate() {
+            (n as u32, Str8(word.to_string())).encode(&mut data);
+        }
+
+        // However it's cut up, the same messages come out.
+        for size in 1..=data.len() {
+            let mut frames = Frames::<(u32, Str8)>::new();
+            let mut got = Vec::new();
+            for chunk in data.chunks(size) {
+                frames.push(chunk);
+                for frame in frames.by_ref() {
+                    got.push(frame.unwrap());
+                }
+            }
+            assert!(frames.pending().is_empty());
+            let got: Vec<_> = got.iter().map(|(n, s)| (*n, s.0.as_str())).collect();
+            let want: Vec<_> = words.iter().enumerate().map(|(n, w)| (n as u32, *w)).collect();
+            assert_eq!(got, want, "in pieces of {}", size);
+        }
+    }
+
+    #[test]
+    fn errors_end_the_stream() {
+        let mut frames = Frames::<Str8>::new();
+        frames.push(b"\x01a\x01\xff\x01b");
+        assert_eq!(frames.next(), Some(Ok(Str8("a".into()))));
+        assert!(matches!(frames.next(), Some(Err(_))));
+        assert_eq!(frames.next(), None);
+        frames.push(b"\x01c");
+        assert_eq!(frames.next(), None);
+    }
+
+    #[test]
+    fn long_frames() {
+        let mut frames = Frames::<Str32>::with_max_frame(8);
+        frames.push(b"\0\0\0\x04abcd\0\0\0\x05ab");
+        assert_eq!(frames.next(), Some(Ok(Str32("abcd".into()))));
+        assert_eq!(frames.next(), None);
+        frames.push(b"c");
+        assert_eq!(frames.next(), None);
+        // That would be nine bytes, even though it's all there.
+        frames.push(b"de");
+        assert!(matches!(frames.next(), Some(Err(_))));
+
+        // However much it says it needs.
+        let mut frames = Frames::<Str32>::with_max_frame(8);
+        frames.push(b"\xff\xff\xff\xff0123");
+        assert_eq!(frames.next(), None);
+        frames.push(b"4");
+        assert!(matches!(frames.next(), Some(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn read_from() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let mut frames = Frames::<u32>::new();
+        tokio::io::AsyncWriteExt::write_all(&mut a, &[0, 0, 1, 0, 0, 0]).await.unwrap();
+        drop(a);
+
+        assert_eq!(frames.read_from(&mut b, 16).await.unwrap(), 6);
+        assert_eq!(frames.next(), Some(Ok(256)));
+        assert_eq!(frames.next(), None);
+        assert_eq!(frames.pending(), &[0, 0]);
+        assert_eq!(frames.read_from(&mut b, 16).await.unwrap(), 0);
+    }
+}
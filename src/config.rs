@@ -0,0 +1,808 @@
+/*!
+Runtime configuration shared by all the servers.
+
+Every server takes the options in [`COMMON`], plus any of its own. Each
+option can be set in any of three places; later ones take precedence:
+
+  * a TOML file named by `--config` (or `PH_CONFIG`), as `name = value`
+    (with either dashes or underscores: `buffer_size = 4096`);
+  * the environment, as `PH_` followed by the name in upper case with
+    underscores for dashes (`PH_BUFFER_SIZE=4096`);
+  * the command line, as `--name value` or `--name=value`.
+
+An option that can be given more than once (like `bind`) takes all its
+values from the highest-precedence place it's set at all. In the
+environment they're separated by commas; in the file they can be an array.
+
+Several servers can also be configured at once, each with its own options
+under its own name; see [`Config::parse_many`].
+*/
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::shutdown::DRAIN_TIMEOUT;
+
+/// Address servers listen on unless told otherwise.
+pub const LOCAL_ADDR: &str = "0.0.0.0:12321";
+/// Default size of socket read buffers.
+pub const BUFFER_SIZE: usize = 1024;
+/// Default capacity of channels between tasks.
+pub const CHANNEL_SIZE: usize = 256;
+
+/// A command-line option (and its environment and config file equivalents).
+pub struct Opt {
+    /// Name, without the leading `--`.
+    pub name: &'static str,
+    /// What to call its argument in the help text, or `None` for a flag that
+    /// doesn't take one.
+    pub arg: Option<&'static str>,
+    /// Whether it can be given more than once.
+    pub many: bool,
+    pub help: &'static str,
+}
+
+/// Options understood by every server.
+pub const COMMON: &[Opt] = &[
+    Opt {
+        name: "bind", arg: Some("ADDR"), many: true,
+        help: "listen on ADDR; may be given more than once (default 0.0.0.0:12321)",
+    },
+    Opt {
+        name: "upstream", arg: Some("ADDR"), many: false,
+        help: "address of the server to proxy to, for servers that do",
+    },
+    Opt {
+        name: "buffer-size", arg: Some("BYTES"), many: false,
+        help: "size of socket read buffers (default 1024)",
+    },
+    Opt {
+        name: "channel-size", arg: Some("N"), many: false,
+        help: "capacity of channels between tasks (default 256)",
+    },
+    Opt {
+        name: "max-connections", arg: Some("N"), many: false,
+        help: "serve at most N clients at once (default unlimited)",
+    },
+    Opt {
+        name: "drain-timeout", arg: Some("SECS"), many: false,
+        help: "on shutdown, wait up to SECS for clients to finish (default 5)",
+    },
+    Opt {
+        name: "config", arg: Some("PATH"), many: false,
+        help: "read options from the TOML file at PATH",
+    },
+];
+
+const FOOTER: &str = "
+Every option can also be set in the environment as PH_<NAME> (for example
+PH_BUFFER_SIZE=4096), or in the TOML file given by --config. The command
+line overrides the environment, which overrides the file.
+";
+
+const MANY_FOOTER: &str = "
+Any of the options above except --config can be given for just one server by
+prefixing it with that server's name, as in --smoke.max-connections 10; the
+bind address can only be given that way. Every option can also be set in the
+environment as PH_<NAME> (for example PH_SMOKE_BIND=0.0.0.0:8000), or in the
+TOML file given by --config, with per-server options in a table named after
+the server. The command line overrides the environment, which overrides the
+file; within each of those, an option for one server overrides the same
+option for all of them.
+";
+
+/// Selects servers when running several at once.
+static SERVERS: Opt = Opt {
+    name: "servers", arg: Some("NAME"), many: true,
+    help: "run the server NAME; may be given more than once",
+};
+
+/// One of several servers configured together by [`Config::parse_many`].
+pub struct Section {
+    pub name: &'static str,
+    /// Short description for the help text.
+    pub about: &'static str,
+    /// The server's own options, in addition to the common ones.
+    pub opts: &'static [Opt],
+    /// Address to bind if none is given.
+    pub bind: &'static str,
+}
+
+/// Option values from one source, keyed by the name they're set with.
+type Layer = BTreeMap<String, Vec<String>>;
+
+/// Every option that can be set, keyed by the name it's set with. When
+/// configuring several servers at once, server-specific options are
+/// prefixed with the server's name and a dot.
+struct Schema(Vec<(String, &'static Opt)>);
+
+impl Schema {
+    fn single(extra: &'static [Opt]) -> Schema {
+        Schema(COMMON.iter().chain(extra.iter())
+            .map(|o| (o.name.to_string(), o))
+            .collect())
+    }
+
+    fn many(sections: &[Section]) -> Schema {
+        let mut keys: Vec<(String, &'static Opt)> = COMMON.iter()
+            .chain(std::iter::once(&SERVERS))
+            .filter(|o| o.name != "bind")
+            .map(|o| (o.name.to_string(), o))
+            .collect();
+        for s in sections.iter() {
+            let opts = COMMON.iter().filter(|o| o.name != "config").chain(s.opts.iter());
+            keys.extend(opts.map(|o| (format!("{}.{}", s.name, o.name), o)));
+        }
+        Schema(keys)
+    }
+
+    fn lookup(&self, key: &str) -> Option<&'static Opt> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, o)| *o)
+    }
+}
+
+fn set(layer: &mut Layer, key: &str, opt: &Opt, value: String) {
+    let values = layer.entry(key.to_string()).or_default();
+    if !opt.many {
+        values.clear();
+    }
+    values.push(value);
+}
+
+/// Read options and positional arguments from command-line arguments (not
+/// including the program name). Returns `None` if help was requested.
+fn args_layer<I>(schema: &Schema, args: I) -> Result<Option<(Layer, Vec<String>)>, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut layer = Layer::new();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        let body = match arg.strip_prefix("--") {
+            Some(body) => body,
+            None => {
+                positional.push(arg);
+                continue;
+            },
+        };
+        let (name, inline) = match body.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (body, None),
+        };
+        let opt = schema.lookup(name)
+            .ok_or_else(|| format!("unrecognized option --{}", name))?;
+
+        let value = match (opt.arg, inline) {
+            (None, None) => "true".to_string(),
+            (None, Some(_)) => { return Err(format!("--{} doesn't take a value", name)); },
+            (Some(_), Some(value)) => value,
+            (Some(arg), None) => args.next().ok_or_else(|| format!(
+                "--{} requires an argument ({})", name, arg
+            ))?,
+        };
+        set(&mut layer, name, opt, value);
+    }
+
+    Ok(Some((layer, positional)))
+}
+
+/// Name of the environment variable for the option set with `key`.
+fn env_var(key: &str) -> String {
+    format!("PH_{}", key.to_uppercase().replace(['-', '.'], "_"))
+}
+
+fn env_layer<F>(schema: &Schema, env: F) -> Layer
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut layer = Layer::new();
+    for (key, opt) in schema.0.iter() {
+        if let Some(value) = env(&env_var(key)) {
+            let values = if opt.many {
+                value.split(',')
+                    .map(|v| v.trim().to_string())
+                    .filter(|v| !v.is_empty())
+                    .collect()
+            } else {
+                vec![value]
+            };
+            layer.insert(key.clone(), values);
+        }
+    }
+    layer
+}
+
+fn scalar(value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(x) => Ok(x.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        x => Err(format!("unsupported {} value", x.type_str())),
+    }
+}
+
+fn file_layer(schema: &Schema, text: &str) -> Result<Layer, String> {
+    let table: toml::Table = text.parse().map_err(|e| format!("{}", &e))?;
+
+    // Flatten tables (one level deep) into dotted keys.
+    let mut entries: Vec<(String, toml::Value)> = Vec::new();
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(t) => {
+                entries.extend(t.into_iter().map(|(k, v)| (format!("{}.{}", &key, &k), v)));
+            },
+            v => { entries.push((key, v)); },
+        }
+    }
+
+    let mut layer = Layer::new();
+    for (key, value) in entries {
+        let name = key.replace('_', "-");
+        let opt = schema.lookup(&name)
+            .ok_or_else(|| format!("unrecognized option {:?}", &key))?;
+        let values = match value {
+            toml::Value::Array(a) if opt.many => {
+                a.into_iter().map(scalar).collect::<Result<Vec<_>, _>>()
+            },
+            x => scalar(x).map(|v| vec![v]),
+        }.map_err(|e| format!("{}: {}", &key, &e))?;
+        layer.insert(name, values);
+    }
+    Ok(layer)
+}
+
+/// Read the environment, and the config file if it or the command line
+/// (already parsed into `cli`) names one, and return them along with `cli`:
+/// file, environment, command line, in increasing order of precedence.
+fn layers<F>(schema: &Schema, cli: Layer, env: F) -> Result<[Layer; 3], String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let env = env_layer(schema, env);
+
+    let path = cli.get("config").or_else(|| env.get("config")).and_then(|v| v.last());
+    let file = match path {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("unable to read {}: {}", path, &e))?;
+            file_layer(schema, &text).map_err(|e| format!("{}: {}", path, &e))?
+        },
+        None => Layer::new(),
+    };
+    Ok([file, env, cli])
+}
+
+/// Combine `layers`, each overriding the ones before it.
+fn merge(layers: [Layer; 3]) -> Layer {
+    let mut values = Layer::new();
+    for layer in layers {
+        values.extend(layer);
+    }
+    values
+}
+
+fn parse_value<T>(name: &str, value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse().map_err(|e| format!("invalid value {:?} for {}: {}", value, name, &e))
+}
+
+/// Print `e` and exit the way the `load` functions do on bad options. For
+/// anything else that stops a server from starting, too.
+pub fn bail(e: &str) -> ! {
+    eprintln!("{}\n\nTry --help for more information.", e);
+    std::process::exit(2);
+}
+
+/// Configuration for a single server.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: Vec<String>,
+    pub upstream: Option<String>,
+    pub buffer_size: usize,
+    pub channel_size: usize,
+    pub max_connections: Option<usize>,
+    pub drain_timeout: Duration,
+    // Values of the server's own options, which it interprets itself.
+    extra: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![LOCAL_ADDR.to_string()],
+            upstream: None,
+            buffer_size: BUFFER_SIZE,
+            channel_size: CHANNEL_SIZE,
+            max_connections: None,
+            drain_timeout: DRAIN_TIMEOUT,
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    fn from_values(mut values: BTreeMap<&'static str, Vec<String>>) -> Result<Config, String> {
+        type Values = BTreeMap<&'static str, Vec<String>>;
+        fn one<T>(values: &mut Values, name: &str) -> Result<Option<T>, String>
+        where
+            T: FromStr,
+            T::Err: Display,
+        {
+            match values.remove(name).and_then(|mut v| v.pop()) {
+                Some(v) => parse_value(name, &v).map(Some),
+                None => Ok(None),
+            }
+        }
+        fn positive(name: &str, n: Option<usize>) -> Result<Option<usize>, String> {
+            match n {
+                Some(0) => Err(format!("{} must be positive", name)),
+                n => Ok(n),
+            }
+        }
+
+        let mut cfg = Config::default();
+        if let Some(bind) = values.remove("bind") {
+            if bind.is_empty() {
+                return Err("no bind addresses".into());
+            }
+            cfg.bind = bind;
+        }
+        cfg.upstream = one(&mut values, "upstream")?;
+        if let Some(n) = positive("buffer-size", one(&mut values, "buffer-size")?)? {
+            cfg.buffer_size = n;
+        }
+        if let Some(n) = positive("channel-size", one(&mut values, "channel-size")?)? {
+            cfg.channel_size = n;
+        }
+        cfg.max_connections = positive(
+            "max-connections", one(&mut values, "max-connections")?
+        )?;
+        if let Some(secs) = one::<f64>(&mut values, "drain-timeout")? {
+            cfg.drain_timeout = Duration::try_from_secs_f64(secs)
+                .map_err(|e| format!("invalid value {} for drain-timeout: {}", secs, &e))?;
+        }
+        values.remove("config");
+        cfg.extra = values;
+
+        Ok(cfg)
+    }
+
+    /**
+    Build a `Config` from command-line arguments (not including the program
+    name), environment variables (looked up with `env`), and the config
+    file, if either of those names one. `extra` are the server's own
+    options, in addition to [`COMMON`].
+
+    Returns `Ok(None)` if the arguments ask for help.
+    */
+    pub fn parse<I, F>(extra: &'static [Opt], args: I, env: F) -> Result<Option<Config>, String>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let schema = Schema::single(extra);
+        let (cli, positional) = match args_layer(&schema, args)? {
+            Some(x) => x,
+            None => { return Ok(None); },
+        };
+        if let Some(arg) = positional.first() {
+            return Err(format!("unexpected argument {:?}", arg));
+        }
+
+        let values = merge(layers(&schema, cli, env)?).into_iter()
+            // Every key is an option name from the schema.
+            .map(|(k, v)| (schema.lookup(&k).unwrap().name, v))
+            .collect();
+        Config::from_values(values).map(Some)
+    }
+
+    /**
+    Build a `Config` for each of several servers, described by `sections`,
+    which are configured together; see [`usage_many`] for how.
+
+    The servers to run are named by the positional arguments, or failing
+    that the `servers` option. Returns their names and `Config`s, in the
+    order given, or `Ok(None)` if the arguments ask for help.
+    */
+    pub fn parse_many<I, F>(
+        sections: &[Section],
+        args: I,
+        env: F,
+    ) -> Result<Option<Vec<(&'static str, Config)>>, String>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let schema = Schema::many(sections);
+        let (cli, positional) = match args_layer(&schema, args)? {
+            Some(x) => x,
+            None => { return Ok(None); },
+        };
+        let layers = layers(&schema, cli, env)?;
+        let names = if positional.is_empty() {
+            merge(layers.clone()).remove("servers").unwrap_or_default()
+        } else {
+            positional
+        };
+
+        let mut configs: Vec<(&'static str, Config)> = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            let s = sections.iter().find(|s| s.name == name)
+                .ok_or_else(|| format!("no such server {:?}", name))?;
+            if configs.iter().any(|(n, _)| n == name) {
+                return Err(format!("server {:?} given more than once", name));
+            }
+
+            // Layer by layer, options for every server, then options for
+            // just this one; so a later layer's options for every server
+            // beat an earlier layer's for just this one.
+            let prefix = format!("{}.", s.name);
+            let mut own: BTreeMap<&'static str, Vec<String>> = BTreeMap::new();
+            own.insert("bind", vec![s.bind.to_string()]);
+            for values in layers.iter() {
+                for (k, v) in values.iter() {
+                    if COMMON.iter().any(|o| o.name == k) {
+                        own.insert(schema.lookup(k).unwrap().name, v.clone());
+                    }
+                }
+                for (k, v) in values.iter() {
+                    if k.starts_with(&prefix) {
+                        own.insert(schema.lookup(k).unwrap().name, v.clone());
+                    }
+                }
+            }
+
+            let cfg = Config::from_values(own).map_err(|e| format!("{}: {}", s.name, &e))?;
+            configs.push((s.name, cfg));
+        }
+
+        Ok(Some(configs))
+    }
+
+    /**
+    Build a `Config` from this process's arguments and environment.
+
+    Prints the help text (headed by `about`) and exits if that's what was
+    asked for; prints the problem and exits if there's anything wrong.
+    */
+    pub fn load(about: &str, extra: &'static [Opt]) -> Config {
+        let mut args = std::env::args();
+        let prog = args.next().unwrap_or_default();
+        match Config::parse(extra, args, |var| std::env::var(var).ok()) {
+            Ok(Some(cfg)) => cfg,
+            Ok(None) => {
+                print!("{}", usage(&prog, about, extra));
+                std::process::exit(0);
+            },
+            Err(e) => bail(&e),
+        }
+    }
+
+    /// Like [`Config::load`], but for several servers at once; see
+    /// [`Config::parse_many`]. Also exits if no servers are selected.
+    pub fn load_many(about: &str, sections: &[Section]) -> Vec<(&'static str, Config)> {
+        let mut args = std::env::args();
+        let prog = args.next().unwrap_or_default();
+        match Config::parse_many(sections, args, |var| std::env::var(var).ok()) {
+            Ok(Some(configs)) if configs.is_empty() => bail("no servers given"),
+            Ok(Some(configs)) => configs,
+            Ok(None) => {
+                print!("{}", usage_many(&prog, about, sections));
+                std::process::exit(0);
+            },
+            Err(e) => bail(&e),
+        }
+    }
+
+    /// Return the value of the server's own option `name`, if it was set.
+    pub fn get<T>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.extra.get(name).and_then(|v| v.last()) {
+            Some(v) => parse_value(name, v).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Return all the values given for the server's own option `name`.
+    pub fn all(&self, name: &str) -> &[String] {
+        self.extra.get(name).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Set the server's own option `name`, as though it had been given
+    /// on the command line.
+    pub fn set(&mut self, name: &'static str, value: impl Display) {
+        self.extra.insert(name, vec![value.to_string()]);
+    }
+}
+
+fn program(prog: &str) -> String {
+    Path::new(prog).file_name()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Write `lines` into `text` as two neat columns.
+fn columns<L: AsRef<str>, R: AsRef<str>>(text: &mut String, lines: &[(L, R)]) {
+    let width = lines.iter().map(|(l, _)| l.as_ref().len()).max().unwrap_or(0);
+    for (left, right) in lines.iter() {
+        // Writing to a `String` can't fail.
+        writeln!(
+            text, "  {:width$}  {}", left.as_ref(), right.as_ref(), width = width
+        ).unwrap();
+    }
+}
+
+fn option_line(prefix: &str, o: &Opt) -> (String, &'static str) {
+    match o.arg {
+        Some(arg) => (format!("--{}{} {}", prefix, o.name, arg), o.help),
+        None => (format!("--{}{}", prefix, o.name), o.help),
+    }
+}
+
+/// Return the `--help` text for a server that takes the `extra` options.
+pub fn usage(prog: &str, about: &str, extra: &[Opt]) -> String {
+    let mut lines: Vec<(String, &str)> = COMMON.iter().chain(extra.iter())
+        .map(|o| option_line("", o))
+        .collect();
+    lines.push(("-h, --help".to_string(), "print this message and exit"));
+
+    let mut text = format!("{}\n\nUsage: {} [OPTIONS]\n\nOptions:\n", about, program(prog));
+    columns(&mut text, &lines);
+    text.push_str(FOOTER);
+    text
+}
+
+/// Return the `--help` text for running several of the servers described
+/// by `sections` at once.
+pub fn usage_many(prog: &str, about: &str, sections: &[Section]) -> String {
+    let mut text = format!(
+        "{}\n\nUsage: {} [OPTIONS] SERVER...\n\nServers:\n", about, program(prog)
+    );
+    let servers: Vec<(String, &str)> = sections.iter()
+        .map(|s| (s.name.to_string(), s.about))
+        .collect();
+    columns(&mut text, &servers);
+
+    text.push_str("\nOptions:\n");
+    let mut lines: Vec<(String, String)> = sections.iter()
+        .map(|s| (
+            format!("--{}.bind ADDR", s.name),
+            format!("listen on ADDR; may be given more than once (default {})", s.bind),
+        ))
+        .collect();
+    lines.extend(
+        COMMON.iter().chain(std::iter::once(&SERVERS))
+            .filter(|o| o.name != "bind")
+            .map(|o| {
+                let (l, h) = option_line("", o);
+                (l, h.to_string())
+            })
+    );
+    for s in sections.iter() {
+        lines.extend(s.opts.iter().map(|o| {
+            let (l, h) = option_line(&format!("{}.", s.name), o);
+            (l, h.to_string())
+        }));
+    }
+    lines.push(("-h, --help".to_string(), "print this message and exit".to_string()));
+    columns(&mut text, &lines);
+
+    text.push_str(MANY_FOOTER);
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EXTRA: &[Opt] = &[
+        Opt { name: "max-digits", arg: Some("N"), many: false, help: "" },
+        Opt { name: "verbose", arg: None, many: false, help: "" },
+    ];
+
+    const SECTIONS: &[Section] = &[
+        Section { name: "smoke", about: "echo", opts: &[], bind: "0.0.0.0:1" },
+        Section { name: "prime", about: "primes", opts: EXTRA, bind: "0.0.0.0:2" },
+    ];
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    fn parse(cli: &str, env: &[(&str, &str)]) -> Result<Option<Config>, String> {
+        let env: BTreeMap<String, String> = env.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::parse(EXTRA, args(cli), |var| env.get(var).cloned())
+    }
+
+    fn parse_many(cli: &str, env: &[(&str, &str)]) -> Result<Vec<(&'static str, Config)>, String> {
+        let env: BTreeMap<String, String> = env.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::parse_many(SECTIONS, args(cli), |var| env.get(var).cloned())
+            .map(Option::unwrap)
+    }
+
+    #[test]
+    fn command_line() {
+        let cfg = parse("", &[]).unwrap().unwrap();
+        assert_eq!(cfg.bind, vec![LOCAL_ADDR]);
+        assert_eq!(cfg.buffer_size, BUFFER_SIZE);
+        assert_eq!(cfg.max_connections, None);
+        assert_eq!(cfg.get::<usize>("max-digits"), Ok(None));
+
+        let cfg = parse(
+            "--bind 127.0.0.1:1 --bind=[::1]:2 --buffer-size 4096 --max-digits=12 --verbose",
+            &[]
+        ).unwrap().unwrap();
+        assert_eq!(cfg.bind, vec!["127.0.0.1:1", "[::1]:2"]);
+        assert_eq!(cfg.buffer_size, 4096);
+        assert_eq!(cfg.get::<usize>("max-digits"), Ok(Some(12)));
+        assert_eq!(cfg.get::<bool>("verbose"), Ok(Some(true)));
+
+        assert!(parse("--help", &[]).unwrap().is_none());
+        assert!(parse("--bind 1:1 -h", &[]).unwrap().is_none());
+
+        assert!(parse("--bogus 3", &[]).is_err());
+        assert!(parse("--bind", &[]).is_err());
+        assert!(parse("--verbose=yes", &[]).is_err());
+        assert!(parse("stray", &[]).is_err());
+        assert!(parse("--buffer-size lots", &[]).is_err());
+        assert!(parse("--channel-size 0", &[]).is_err());
+        assert!(parse("--drain-timeout -1", &[]).is_err());
+        assert_eq!(
+            parse("--drain-timeout 0.5", &[]).unwrap().unwrap().drain_timeout,
+            Duration::from_millis(500)
+        );
+        assert!(parse("--max-digits 12", &[]).unwrap().unwrap().get::<i8>("max-digits").is_ok());
+        assert!(parse("--max-digits x", &[]).unwrap().unwrap().get::<usize>("max-digits").is_err());
+    }
+
+    #[test]
+    fn layers() {
+        let env = [
+            ("PH_BIND", "127.0.0.1:1, 127.0.0.1:2"),
+            ("PH_MAX_CONNECTIONS", "5"),
+            ("PH_MAX_DIGITS", "7"),
+        ];
+        let cfg = parse("", &env).unwrap().unwrap();
+        assert_eq!(cfg.bind, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(cfg.max_connections, Some(5));
+        assert_eq!(cfg.get::<usize>("max-digits"), Ok(Some(7)));
+
+        // The command line replaces, rather than adds to, the environment.
+        let cfg = parse("--bind 127.0.0.1:3 --max-digits 8", &env).unwrap().unwrap();
+        assert_eq!(cfg.bind, vec!["127.0.0.1:3"]);
+        assert_eq!(cfg.max_connections, Some(5));
+        assert_eq!(cfg.get::<usize>("max-digits"), Ok(Some(8)));
+
+        let path = std::env::temp_dir().join(format!("ph-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "
+            bind = ['127.0.0.1:4', '127.0.0.1:5']
+            upstream = 'localhost:6'
+            buffer_size = 2048
+            max-digits = 9
+        ").unwrap();
+        let path_arg = format!("--config {}", path.display());
+
+        let cfg = parse(&path_arg, &[]).unwrap().unwrap();
+        assert_eq!(cfg.bind, vec!["127.0.0.1:4", "127.0.0.1:5"]);
+        assert_eq!(cfg.upstream.as_deref(), Some("localhost:6"));
+        assert_eq!(cfg.buffer_size, 2048);
+        assert_eq!(cfg.get::<usize>("max-digits"), Ok(Some(9)));
+
+        let cfg = parse(&path_arg, &env).unwrap().unwrap();
+        assert_eq!(cfg.bind, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(cfg.buffer_size, 2048);
+
+        let env = [("PH_CONFIG", path.to_str().unwrap())];
+        let cfg = parse("--buffer-size 512", &env).unwrap().unwrap();
+        assert_eq!(cfg.upstream.as_deref(), Some("localhost:6"));
+        assert_eq!(cfg.buffer_size, 512);
+
+        std::fs::write(&path, "nonsense = 3").unwrap();
+        assert!(parse(&path_arg, &[]).is_err());
+        std::fs::write(&path, "upstream = ['a', 'b']").unwrap();
+        assert!(parse(&path_arg, &[]).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse(&path_arg, &[]).is_err());
+    }
+
+    #[test]
+    fn many() {
+        let got = parse_many(
+            "prime smoke --max-connections 5 --smoke.max-connections 2 \
+             --prime.max-digits 9 --prime.bind 127.0.0.1:3",
+            &[]
+        ).unwrap();
+        let names: Vec<&str> = got.iter().map(|(n, _)| *n).collect();
+        assert_eq!(names, vec!["prime", "smoke"]);
+        let (prime, smoke) = (&got[0].1, &got[1].1);
+        assert_eq!(prime.bind, vec!["127.0.0.1:3"]);
+        assert_eq!(smoke.bind, vec!["0.0.0.0:1"]);
+        assert_eq!(prime.max_connections, Some(5));
+        assert_eq!(smoke.max_connections, Some(2));
+        assert_eq!(prime.get::<usize>("max-digits"), Ok(Some(9)));
+        assert_eq!(smoke.get::<usize>("max-digits"), Ok(None));
+
+        let env = [("PH_SERVERS", "smoke"), ("PH_SMOKE_BUFFER_SIZE", "10")];
+        let got = parse_many("", &env).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].0, "smoke");
+        assert_eq!(got[0].1.buffer_size, 10);
+        // Naming servers on the command line overrides `servers`.
+        assert_eq!(parse_many("prime", &env).unwrap()[0].0, "prime");
+        assert!(parse_many("", &[]).unwrap().is_empty());
+
+        let path = std::env::temp_dir().join(format!("ph-many-{}.toml", std::process::id()));
+        std::fs::write(&path, "
+            servers = ['prime']
+            channel_size = 8
+            [prime]
+            max_digits = 4
+            channel-size = 16
+            [smoke]
+            bind = ['127.0.0.1:5']
+        ").unwrap();
+        let got = parse_many(&format!("smoke prime --config {}", path.display()), &[]).unwrap();
+        assert_eq!(got[0].1.bind, vec!["127.0.0.1:5"]);
+        assert_eq!(got[0].1.channel_size, 8);
+        assert_eq!(got[1].1.channel_size, 16);
+        assert_eq!(got[1].1.get::<usize>("max-digits"), Ok(Some(4)));
+
+        // Where an option comes from matters more than what it's for.
+        let cli = format!("prime --config {} --channel-size 32", path.display());
+        assert_eq!(parse_many(&cli, &[]).unwrap()[0].1.channel_size, 32);
+        let env = [("PH_CHANNEL_SIZE", "64")];
+        assert_eq!(parse_many(&cli, &env).unwrap()[0].1.channel_size, 32);
+        let cli = format!("prime --config {}", path.display());
+        assert_eq!(parse_many(&cli, &env).unwrap()[0].1.channel_size, 64);
+        let env = [("PH_CHANNEL_SIZE", "64"), ("PH_PRIME_CHANNEL_SIZE", "128")];
+        assert_eq!(parse_many(&cli, &env).unwrap()[0].1.channel_size, 128);
+        let cli = format!("prime --config {} --channel-size 32", path.display());
+        assert_eq!(parse_many(&cli, &env).unwrap()[0].1.channel_size, 32);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(parse_many("bogus", &[]).is_err());
+        assert!(parse_many("smoke smoke", &[]).is_err());
+        assert!(parse_many("smoke --bind 127.0.0.1:3", &[]).is_err());
+        assert!(parse_many("smoke --smoke.max-digits 3", &[]).is_err());
+        assert!(parse_many("smoke --smoke.config x", &[]).is_err());
+        assert!(parse_many("smoke --smoke.buffer-size 0", &[]).is_err());
+    }
+
+    #[test]
+    fn help() {
+        let text = usage("/usr/bin/01_prime", "Prime Time", EXTRA);
+        assert!(text.starts_with("Prime Time\n\nUsage: 01_prime [OPTIONS]\n"));
+        assert!(text.contains("\n  --bind ADDR "));
+        assert!(text.contains("\n  --max-digits N "));
+        assert!(text.contains("\n  --verbose "));
+        assert!(text.contains("\n  -h, --help "));
+
+        let text = usage_many("ph", "All of them", SECTIONS);
+        assert!(text.starts_with("All of them\n\nUsage: ph [OPTIONS] SERVER...\n"));
+        assert!(text.contains("\n  prime  primes\n"));
+        assert!(text.contains("\n  --smoke.bind ADDR "));
+        assert!(text.contains("(default 0.0.0.0:2)"));
+        assert!(text.contains("\n  --prime.max-digits N "));
+        assert!(!text.contains("\n  --bind "));
+    }
+}
@@ -0,0 +1,89 @@
+/*!
+Protohackers Problem 04: Unusual Database Program
+
+A key-value store over UDP. A datagram containing an `=` inserts (the key
+is everything up to the first `=`); any other datagram retrieves.
+*/
+use std::collections::HashMap;
+use tokio::net::UdpSocket;
+
+use crate::{config::Config, shutdown::Shutdown};
+
+static VERSION_REQUEST: &[u8] = b"version";
+static VERSION: &[u8] = b"version=Ken's Key-Value Store v -0.1";
+
+/// Serve requests until there's an error with the socket.
+pub async fn run(
+    sock: &UdpSocket,
+    buff: &mut [u8],
+    db: &mut HashMap<Vec<u8>, Vec<u8>>
+) -> std::io::Result<()> {
+    log::trace!("run() called");
+
+    loop {
+        let (len, addr) = sock.recv_from(buff).await?;
+        let data = &buff[..len];
+        log::debug!("rec'd {} bytes: {:?}", len, &String::from_utf8_lossy(data));
+
+        if data == VERSION_REQUEST {
+            sock.send_to(VERSION, addr).await?;
+            log::debug!("Sent VERSION message.");
+            continue;
+        }
+
+        if let Some(n) = data.iter().position(|&b| b == b'=') {
+            if &data[..n] == VERSION_REQUEST {
+                // Let this packet hit the floooor.
+                continue;
+            }
+
+            let key = Vec::from(&data[..n]);
+            let val = Vec::from(&data[(n+1)..]);
+
+            log::debug!(
+                "Inserting {:?}={:?}.",
+                &String::from_utf8_lossy(&key),
+                &String::from_utf8_lossy(&val)
+            );
+
+            db.insert(key, val);
+
+        } else {
+            if let Some(val) = db.get(data) {
+                let length = val.len() + data.len() + 1;
+                let mut response = Vec::with_capacity(length);
+
+                response.extend_from_slice(data);
+                response.push(b'=');
+                response.extend_from_slice(val);
+
+                log::debug!(
+                    "Sending response: {}",
+                    &String::from_utf8_lossy(&response)
+                );
+
+                sock.send_to(&response, addr).await?;
+            }
+            // Otherwise, we just drop it on the floor.
+        }
+    }
+}
+
+/// Serve requests on `sock` until `shutdown`, logging (and otherwise
+/// ignoring) any socket errors.
+///
+/// Datagrams longer than `cfg.buffer_size` get truncated; the spec promises
+/// they'll all be shorter than 1000 bytes.
+pub async fn serve(sock: UdpSocket, cfg: Config, shutdown: Shutdown) {
+    let mut buff = vec![0u8; cfg.buffer_size];
+    let mut db: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            res = run(&sock, &mut buff, &mut db) => if let Err(e) = res {
+                log::error!("{}", &e);
+            },
+            _ = shutdown.wait() => { break; },
+        }
+    }
+}
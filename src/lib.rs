@@ -1 +1,12 @@
-pub mod primes;
\ No newline at end of file
+pub mod chat;
+pub mod codec;
+pub mod config;
+pub mod kvdb;
+pub mod means;
+pub mod mob;
+pub mod net;
+pub mod primes;
+pub mod primetime;
+pub mod shutdown;
+pub mod smoke;
+pub mod supervisor;
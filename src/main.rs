@@ -1,3 +1,26 @@
-fn main() {
-    println!("Hello, world!");
+/*!
+Run any set of the Protohackers servers at once, in one process:
+
+```text
+ph smoke prime means --prime.max-digits 2000 --mob.upstream localhost:12323
+```
+
+Run with `--help` for the list of servers and options; the servers
+themselves live in the `ph` library.
+*/
+use ph::{config::Config, shutdown, supervisor};
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    env_logger::init();
+
+    let configs = Config::load_many("Protohackers servers", supervisor::SECTIONS);
+    match supervisor::run(configs, shutdown::signal()).await {
+        Ok(dropped) => shutdown::report(&dropped),
+        Err(e) => {
+            log::error!("{}", &e);
+            eprintln!("{}", &e);
+            std::process::exit(1);
+        },
+    }
 }
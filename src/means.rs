@@ -0,0 +1,1771 @@
+/*!
+Protohackers Problem 2: Means to an End
+
+Collect timestamped price messages from each client and supply averages
+over given ranges.
+
+# Extensions
+
+With `--extensions`, the server understands some more kinds of message.
+They're all framed the same way as `I` and `Q`: one byte saying what the
+message is, followed by two big-endian `i32`s. Where those are a `begin`
+and `end`, they mean the same as they do for `Q`, and an empty range gives
+an answer of 0.
+
+| Type | Fields        | Response                                         |
+|------|---------------|--------------------------------------------------|
+| `L`  | `begin` `end` | lowest price, as an `i32`                        |
+| `H`  | `begin` `end` | highest price, as an `i32`                       |
+| `D`  | `begin` `end` | median price (the mean of the middle two if there's an even number, rounded as `--rounding` says), as an `i32` |
+| `C`  | `begin` `end` | how many prices there are, as an `i32`           |
+| `S`  | `begin` `end` | sum of the prices, as an `i64`                   |
+| `W`  | `width` `_`   | none; sets the window width for `B` (default 1)  |
+| `B`  | `begin` `end` | the number of windows N, as an `i32`, then N `i32` means |
+
+`B` splits `begin..=end` into consecutive windows `width` timestamps wide
+(the last one may be narrower), and responds with the mean of each. Asking
+for more than [`MAX_WINDOWS`] windows, or setting a width that isn't
+positive, gets the client disconnected, as does any message type the
+server doesn't understand.
+
+# Shared history
+
+Normally each session's prices are its own, and are forgotten when it
+disconnects, as the spec says. With `--history PATH`, a session can
+instead start by sending a `T` message whose first field is an asset tag
+(any four bytes), and from then on it works with that asset's price series,
+which it shares with every other session that sends the same tag. Every
+price inserted into a tagged series is appended to the log at `PATH`, and
+the series are read back from there when the server starts. Sessions that
+don't send a `T` first are private, as usual; sending one later gets the
+client disconnected.
+
+The log is just a sequence of 12-byte records: tag, timestamp and price,
+each as four big-endian bytes.
+
+# Memory budgets
+
+`--session-memory` caps how much memory any one session's prices can take
+up, and `--total-memory` how much all of them together can. (These count
+[`ENTRY_SIZE`] bytes per price, which is close, if not exact.) What
+happens to an insert that would go over budget depends on `--over-budget`:
+the client can be disconnected, the insert can be ignored, or the session's
+oldest prices (those with the earliest timestamps) can be dropped to make
+room. Replacing a price never goes over budget.
+
+A shared series kept with `--history` is held to the session budget
+however many sessions add to it, and its prices (including those read back
+from the log) count toward the total. Its prices are in the log for good,
+so it can't drop any to make room; with `--over-budget evict`, an insert
+that doesn't fit is ignored instead.
+*/
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    hash::BuildHasher,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::{
+    codec::{self, Decode, Encode, Frames, Reader},
+    config::{Config, Opt},
+    net::Listeners,
+    shutdown::Shutdown,
+};
+
+/// The means server's own options.
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "extensions", arg: None, many: false,
+        help: "understand the extra message types (min, max, median, count, sum, windows)",
+    },
+    Opt {
+        name: "duplicates", arg: Some("POLICY"), many: false,
+        help: "when a timestamp repeats: reject (and disconnect), first, last or all (default last)",
+    },
+    Opt {
+        name: "rounding", arg: Some("MODE"), many: false,
+        help: "how means round: truncate (toward zero), floor or nearest (default truncate)",
+    },
+    Opt {
+        name: "history", arg: Some("PATH"), many: false,
+        help: "let sessions share price series by asset tag, kept in the log at PATH",
+    },
+    Opt {
+        name: "session-memory", arg: Some("BYTES"), many: false,
+        help: "most memory one session's prices may use (default unlimited)",
+    },
+    Opt {
+        name: "total-memory", arg: Some("BYTES"), many: false,
+        help: "most memory all sessions' prices together may use (default unlimited)",
+    },
+    Opt {
+        name: "over-budget", arg: Some("ACTION"), many: false,
+        help: "when an insert would go over budget: disconnect, reject (ignore it) or evict (the oldest prices) (default disconnect)",
+    },
+];
+
+/// How often to log how many prices sessions are holding.
+const USAGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most windows a single `B` message can ask for.
+pub const MAX_WINDOWS: i64 = 10_000;
+
+/// What to do with a price for a timestamp that already has one. The spec
+/// says this is undefined, so any of them will do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Treat it as an error, and disconnect the client.
+    Reject,
+    /// Ignore it, and keep the price that was already there.
+    First,
+    /// Replace the price that was already there.
+    #[default]
+    Last,
+    /// Keep them both, and count them both in any query.
+    All,
+}
+
+impl FromStr for Duplicates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Duplicates::Reject),
+            "first" => Ok(Duplicates::First),
+            "last" => Ok(Duplicates::Last),
+            "all" => Ok(Duplicates::All),
+            _ => Err(format!("unknown duplicate timestamp policy {:?}", s)),
+        }
+    }
+}
+
+/// How to round a mean that isn't a whole number. The spec allows either
+/// way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// Toward zero.
+    #[default]
+    Truncate,
+    /// Toward negative infinity.
+    Floor,
+    /// To the nearest whole number, with halves going away from zero.
+    Nearest,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Rounding::Truncate),
+            "floor" => Ok(Rounding::Floor),
+            "nearest" => Ok(Rounding::Nearest),
+            _ => Err(format!("unknown rounding mode {:?}", s)),
+        }
+    }
+}
+
+impl Rounding {
+    /// Divide `sum` by `n`, which must be positive, rounding this way.
+    pub fn divide(&self, sum: i64, n: i64) -> i64 {
+        match self {
+            Rounding::Truncate => sum / n,
+            Rounding::Floor => sum.div_euclid(n),
+            Rounding::Nearest => {
+                // Twice the sum might not fit in an i64.
+                let (sum, n) = (sum as i128, n as i128);
+                let q = (2 * sum.abs() + n) / (2 * n);
+                (q * sum.signum()) as i64
+            },
+        }
+    }
+}
+
+/// What to do about an insert that would go over a memory budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverBudget {
+    /// Disconnect the client.
+    #[default]
+    Disconnect,
+    /// Ignore the insert.
+    Reject,
+    /// Drop the session's oldest prices until there's room. (A shared
+    /// series can't, so this ignores the insert instead.)
+    Evict,
+}
+
+impl FromStr for OverBudget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disconnect" => Ok(OverBudget::Disconnect),
+            "reject" => Ok(OverBudget::Reject),
+            "evict" => Ok(OverBudget::Evict),
+            _ => Err(format!("unknown over-budget action {:?}", s)),
+        }
+    }
+}
+
+/// Everything about how to serve that isn't common to all the servers.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub extensions: bool,
+    pub duplicates: Duplicates,
+    pub rounding: Rounding,
+    /// Set if sessions can share price series; see [`History`].
+    pub history: Option<Arc<History>>,
+    /// Most memory, in bytes, one session's prices may use.
+    pub session_memory: Option<usize>,
+    /// Most memory, in bytes, all sessions' prices together may use.
+    pub total_memory: Option<usize>,
+    pub over_budget: OverBudget,
+}
+
+impl Options {
+    /// Get the means server's [`OPTS`] out of `cfg`. With `--history`, this
+    /// opens the log and reads it back in.
+    pub fn from_config(cfg: &Config) -> Result<Options, String> {
+        let duplicates = cfg.get("duplicates")?.unwrap_or_default();
+        let rounding = cfg.get("rounding")?.unwrap_or_default();
+        let history = match cfg.get::<PathBuf>("history")? {
+            Some(path) => Some(Arc::new(History::open(&path, duplicates, rounding)?)),
+            None => None,
+        };
+        Ok(Options {
+            extensions: cfg.get("extensions")?.unwrap_or(false),
+            duplicates,
+            rounding,
+            history,
+            session_memory: cfg.get("session-memory")?,
+            total_memory: cfg.get("total-memory")?,
+            over_budget: cfg.get("over-budget")?.unwrap_or_default(),
+        })
+    }
+
+    /// Most prices one session may hold.
+    fn session_entries(&self) -> Option<usize> {
+        self.session_memory.map(|bytes| bytes / ENTRY_SIZE)
+    }
+
+    /// Most prices all sessions together may hold.
+    fn total_entries(&self) -> Option<usize> {
+        self.total_memory.map(|bytes| bytes / ENTRY_SIZE)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Insert {
+    pub timestamp: i32,
+    pub price: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Query {
+    pub begin: i32,
+    pub end: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Msg {
+    I(Insert),
+    Q(Query),
+    // The rest are all extensions.
+    Min(Query),
+    Max(Query),
+    Median(Query),
+    Count(Query),
+    Sum(Query),
+    Width(i32),
+    Windows(Query),
+    /// Only with `--history`.
+    Tag(u32),
+}
+
+/// Return the mean of the prices with timestamps in `low..=high`
+/// (truncated toward zero), or 0 if there aren't any.
+///
+/// This takes time proportional to how many prices are in the range; a
+/// [`Prices`] can do better.
+pub fn range_average(map: &BTreeMap<i32, i32>, low: i32, high: i32) -> i32 {
+    if high < low { return 0; }
+
+    let mut tot: i64 = 0;
+    let mut n: i64 = 0;
+
+    for (_, &val) in map.range(low..=high) {
+        tot += val as i64;
+        n += 1;
+    }
+
+    if n == 0 { return 0; }
+    (tot / n) as i32
+}
+
+/// Stands in for a missing child in [`Prices`].
+const NIL: u32 = u32::MAX;
+
+/// Roughly how much memory each price in a [`Prices`] takes up.
+pub const ENTRY_SIZE: usize = std::mem::size_of::<Node>();
+
+/// Some prices, boiled down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub count: u32,
+    pub sum: i64,
+    /// Only meaningful if `count` isn't 0.
+    pub min: i32,
+    /// Only meaningful if `count` isn't 0.
+    pub max: i32,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary { count: 0, sum: 0, min: i32::MAX, max: i32::MIN }
+    }
+}
+
+impl Summary {
+    fn add(&mut self, other: &Summary) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    fn add_price(&mut self, price: i32) {
+        self.add(&Summary { count: 1, sum: price as i64, min: price, max: price });
+    }
+
+    /// The mean, truncated toward zero, or 0 if there's nothing to average.
+    pub fn mean(&self) -> i32 {
+        self.mean_rounded(Rounding::Truncate)
+    }
+
+    /// The mean, rounded `rounding`'s way, or 0 if there's nothing to
+    /// average.
+    pub fn mean_rounded(&self, rounding: Rounding) -> i32 {
+        if self.count == 0 { return 0; }
+        rounding.divide(self.sum, self.count as i64) as i32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    timestamp: i32,
+    price: i32,
+    priority: u64,
+    left: u32,
+    right: u32,
+    /// Everything in the subtree rooted here.
+    summary: Summary,
+}
+
+/**
+One client's prices, by timestamp.
+
+This is a treap (a binary search tree kept balanced by giving each node a
+random priority, and keeping the tree heap-ordered by those) where every
+node also keeps a [`Summary`] of the prices in its subtree. That makes
+inserting a price, and finding the mean (or min, max, count or sum) over a
+range of timestamps, take O(log n) time.
+
+What happens when a timestamp turns up twice depends on the
+[`Duplicates`] policy, and how means get rounded on the [`Rounding`] mode;
+by default, as with a `BTreeMap`, the later price replaces the earlier one,
+and means are truncated toward zero.
+*/
+#[derive(Debug, Clone)]
+pub struct Prices {
+    nodes: Vec<Node>,
+    /// Slots in `nodes` that have been removed, to be reused.
+    free: Vec<u32>,
+    root: u32,
+    /// Priorities are hashes of timestamps (and how many inserts came before,
+    /// which keeps them distinct for duplicates) with a random key, so
+    /// clients can't choose timestamps that unbalance the tree.
+    hasher: RandomState,
+    inserts: u64,
+    duplicates: Duplicates,
+    rounding: Rounding,
+}
+
+impl Default for Prices {
+    fn default() -> Self {
+        Prices::with_rules(Duplicates::default(), Rounding::default())
+    }
+}
+
+impl Prices {
+    pub fn new() -> Prices {
+        Prices::default()
+    }
+
+    pub fn with_rules(duplicates: Duplicates, rounding: Rounding) -> Prices {
+        Prices {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            root: NIL,
+            hasher: RandomState::new(),
+            inserts: 0,
+            duplicates,
+            rounding,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root == NIL
+    }
+
+    fn node(&self, n: u32) -> &Node {
+        &self.nodes[n as usize]
+    }
+
+    fn node_mut(&mut self, n: u32) -> &mut Node {
+        &mut self.nodes[n as usize]
+    }
+
+    fn summary(&self, n: u32) -> Summary {
+        if n == NIL { Summary::default() } else { self.node(n).summary }
+    }
+
+    /// Recalculate the summary at `n` from its children.
+    fn update(&mut self, n: u32) {
+        let Node { left, right, price, .. } = *self.node(n);
+        let mut summary = self.summary(left);
+        summary.add_price(price);
+        summary.add(&self.summary(right));
+        self.node_mut(n).summary = summary;
+    }
+
+    /// Make `n`'s left child the root of its subtree, and return it.
+    fn rotate_right(&mut self, n: u32) -> u32 {
+        let l = self.node(n).left;
+        self.node_mut(n).left = self.node(l).right;
+        self.node_mut(l).right = n;
+        self.update(n);
+        self.update(l);
+        l
+    }
+
+    /// Make `n`'s right child the root of its subtree, and return it.
+    fn rotate_left(&mut self, n: u32) -> u32 {
+        let r = self.node(n).right;
+        self.node_mut(n).right = self.node(r).left;
+        self.node_mut(r).left = n;
+        self.update(n);
+        self.update(r);
+        r
+    }
+
+    /// Insert into the subtree rooted at `n`, returning its new root.
+    fn insert_under(&mut self, n: u32, timestamp: i32, price: i32) -> u32 {
+        if n == NIL {
+            let node = Node {
+                timestamp,
+                price,
+                priority: self.hasher.hash_one((timestamp, self.inserts)),
+                left: NIL,
+                right: NIL,
+                summary: Summary { count: 1, sum: price as i64, min: price, max: price },
+            };
+            self.inserts += 1;
+            return match self.free.pop() {
+                Some(n) => {
+                    *self.node_mut(n) = node;
+                    n
+                },
+                None => {
+                    self.nodes.push(node);
+                    (self.nodes.len() - 1) as u32
+                },
+            };
+        }
+
+        let node = *self.node(n);
+        if timestamp == node.timestamp && self.duplicates != Duplicates::All {
+            self.node_mut(n).price = price;
+            self.update(n);
+            n
+        } else if timestamp < node.timestamp {
+            let l = self.insert_under(node.left, timestamp, price);
+            self.node_mut(n).left = l;
+            if self.node(l).priority > node.priority {
+                self.rotate_right(n)
+            } else {
+                self.update(n);
+                n
+            }
+        } else {
+            let r = self.insert_under(node.right, timestamp, price);
+            self.node_mut(n).right = r;
+            if self.node(r).priority > node.priority {
+                self.rotate_left(n)
+            } else {
+                self.update(n);
+                n
+            }
+        }
+    }
+
+    fn contains(&self, timestamp: i32) -> bool {
+        let mut n = self.root;
+        while n != NIL {
+            let node = self.node(n);
+            if timestamp == node.timestamp {
+                return true;
+            }
+            n = if timestamp < node.timestamp { node.left } else { node.right };
+        }
+        false
+    }
+
+    /// Whether inserting a price for `timestamp` would add to how many
+    /// there are (rather than replacing one, or being ignored or rejected).
+    pub fn would_grow(&self, timestamp: i32) -> bool {
+        self.duplicates == Duplicates::All || !self.contains(timestamp)
+    }
+
+    /// Remove the node with the earliest timestamp from the subtree at `n`,
+    /// returning the subtree's new root and the removed node.
+    fn pop_first_under(&mut self, n: u32) -> (u32, u32) {
+        let node = *self.node(n);
+        if node.left == NIL {
+            return (node.right, n);
+        }
+        let (left, removed) = self.pop_first_under(node.left);
+        self.node_mut(n).left = left;
+        self.update(n);
+        (n, removed)
+    }
+
+    /// Remove the price with the earliest timestamp, and return it (as
+    /// `(timestamp, price)`).
+    pub fn pop_first(&mut self) -> Option<(i32, i32)> {
+        if self.root == NIL {
+            return None;
+        }
+        let (root, removed) = self.pop_first_under(self.root);
+        self.root = root;
+        self.free.push(removed);
+        let node = self.node(removed);
+        Some((node.timestamp, node.price))
+    }
+
+    /// Whether inserting a price for `timestamp` would change anything,
+    /// given the [`Duplicates`] policy. It's an error if the insert would
+    /// be rejected.
+    pub fn check(&self, timestamp: i32) -> Result<bool, String> {
+        match self.duplicates {
+            Duplicates::Reject if self.contains(timestamp) => {
+                Err(format!("duplicate timestamp {}", timestamp))
+            },
+            Duplicates::First if self.contains(timestamp) => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Insert a price, following the [`Duplicates`] policy if there's
+    /// already one for `timestamp`, and return whether anything changed
+    /// (as for [`check`](Prices::check)).
+    pub fn insert(&mut self, timestamp: i32, price: i32) -> Result<bool, String> {
+        if !self.check(timestamp)? {
+            return Ok(false);
+        }
+        self.root = self.insert_under(self.root, timestamp, price);
+        Ok(true)
+    }
+
+    /// Add everything in the subtree at `n` with timestamps in
+    /// `low..=high` to `acc`. A bound of `None` means the whole subtree is
+    /// known to be within it.
+    ///
+    /// Once the path splits, one bound or the other is always `None`, and
+    /// whole subtrees get added without looking inside, so this only
+    /// visits O(log n) nodes.
+    fn summarize(&self, n: u32, low: Option<i32>, high: Option<i32>, acc: &mut Summary) {
+        if n == NIL { return; }
+        let node = self.node(n);
+        match (low, high) {
+            (None, None) => { acc.add(&node.summary); },
+            (Some(low), _) if node.timestamp < low => {
+                self.summarize(node.right, Some(low), high, acc);
+            },
+            (_, Some(high)) if node.timestamp > high => {
+                self.summarize(node.left, low, Some(high), acc);
+            },
+            _ => {
+                self.summarize(node.left, low, None, acc);
+                acc.add_price(node.price);
+                self.summarize(node.right, None, high, acc);
+            },
+        }
+    }
+
+    /// Summarize the prices with timestamps in `low..=high`.
+    pub fn range(&self, low: i32, high: i32) -> Summary {
+        let mut summary = Summary::default();
+        if low <= high {
+            self.summarize(self.root, Some(low), Some(high), &mut summary);
+        }
+        summary
+    }
+
+    /// Same as [`range_average`], but in O(log n) time, and rounded
+    /// according to the [`Rounding`] mode.
+    pub fn average(&self, low: i32, high: i32) -> i32 {
+        self.range(low, high).mean_rounded(self.rounding)
+    }
+
+    /// Push the prices with timestamps in `low..=high`, from the subtree at
+    /// `n`, onto `out`.
+    fn collect(&self, n: u32, low: i32, high: i32, out: &mut Vec<i32>) {
+        if n == NIL { return; }
+        let node = self.node(n);
+        // With duplicates, equal timestamps can be on either side.
+        if node.timestamp >= low {
+            self.collect(node.left, low, high, out);
+        }
+        if (low..=high).contains(&node.timestamp) {
+            out.push(node.price);
+        }
+        if node.timestamp <= high {
+            self.collect(node.right, low, high, out);
+        }
+    }
+
+    /// The median of the prices with timestamps in `low..=high` (with an
+    /// even number of them, the mean of the middle two, rounded according
+    /// to the [`Rounding`] mode), or 0 if there aren't any.
+    ///
+    /// Unlike everything else here, this takes time proportional to how many
+    /// prices are in the range.
+    pub fn median(&self, low: i32, high: i32) -> i32 {
+        let mut prices = Vec::new();
+        if low <= high {
+            self.collect(self.root, low, high, &mut prices);
+        }
+        let n = prices.len();
+        if n == 0 { return 0; }
+
+        let (below, &mut upper, _) = prices.select_nth_unstable(n / 2);
+        if n % 2 == 1 {
+            return upper;
+        }
+        let lower = *below.iter().max().unwrap();
+        self.rounding.divide(lower as i64 + upper as i64, 2) as i32
+    }
+
+    /// Split `begin..=end` into consecutive windows `width` wide, and return
+    /// the mean of each.
+    pub fn windows(&self, begin: i32, end: i32, width: i32) -> Vec<i32> {
+        let (end, width) = (end as i64, width.max(1) as i64);
+        let mut means = Vec::new();
+        let mut start = begin as i64;
+        while start <= end {
+            let stop = (start + width - 1).min(end);
+            means.push(self.average(start as i32, stop as i32));
+            start += width;
+        }
+        means
+    }
+}
+
+/// How many windows `width` wide it takes to cover `begin..=end`.
+pub fn window_count(begin: i32, end: i32, width: i32) -> i64 {
+    if end < begin || width < 1 { return 0; }
+    let len = end as i64 - begin as i64 + 1;
+    (len + width as i64 - 1) / width as i64
+}
+
+impl Msg {
+    /// The message's type, which is its first byte on the wire.
+    pub fn kind(&self) -> u8 {
+        match self {
+            Msg::I(_) => b'I',
+            Msg::Q(_) => b'Q',
+            Msg::Min(_) => b'L',
+            Msg::Max(_) => b'H',
+            Msg::Median(_) => b'D',
+            Msg::Count(_) => b'C',
+            Msg::Sum(_) => b'S',
+            Msg::Width(_) => b'W',
+            Msg::Windows(_) => b'B',
+            Msg::Tag(_) => b'T',
+        }
+    }
+
+    /// Return `self` if it's a stock message, one of the
+    /// [extensions](self#extensions) and `extensions` is set, or an asset
+    /// tag and `tags` is set.
+    pub fn allowed(self, extensions: bool, tags: bool) -> Result<Msg, String> {
+        let ok = match self {
+            Msg::I(_) | Msg::Q(_) => true,
+            Msg::Tag(_) => tags,
+            _ => extensions,
+        };
+        if ok {
+            Ok(self)
+        } else {
+            Err(unrecognized(self.kind()))
+        }
+    }
+
+    /// Decode one of the stock Protohackers messages.
+    pub fn decode(data: &[u8; 9]) -> Result<Msg, String> {
+        codec::decode_exact::<Msg>(data)?.allowed(false, false)
+    }
+
+    /// Decode a stock message or one of the [extensions](self#extensions).
+    pub fn decode_extended(data: &[u8; 9]) -> Result<Msg, String> {
+        codec::decode_exact::<Msg>(data)?.allowed(true, false)
+    }
+}
+
+fn unrecognized(kind: u8) -> String {
+    format!("unrecognized behavior: {} (expected {} or {})", kind, b'I', b'Q')
+}
+
+/// Every message is a type byte and two big-endian `i32`s, some of which
+/// go unused.
+impl Decode for Msg {
+    fn decode(r: &mut Reader) -> Result<Self, codec::Error> {
+        let (kind, a, b): (u8, i32, i32) = r.read()?;
+        let q = Query { begin: a, end: b };
+
+        let msg = match kind {
+            b'I' => Msg::I(Insert { timestamp: a, price: b }),
+            b'Q' => Msg::Q(q),
+            b'L' => Msg::Min(q),
+            b'H' => Msg::Max(q),
+            b'D' => Msg::Median(q),
+            b'C' => Msg::Count(q),
+            b'S' => Msg::Sum(q),
+            b'W' => Msg::Width(a),
+            b'B' => Msg::Windows(q),
+            b'T' => Msg::Tag(a as u32),
+            x => { return Err(unrecognized(x).into()); },
+        };
+        Ok(msg)
+    }
+}
+
+impl Encode for Msg {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (a, b) = match *self {
+            Msg::I(m) => (m.timestamp, m.price),
+            Msg::Q(q) | Msg::Min(q) | Msg::Max(q) | Msg::Median(q)
+            | Msg::Count(q) | Msg::Sum(q) | Msg::Windows(q) => (q.begin, q.end),
+            Msg::Width(w) => (w, 0),
+            Msg::Tag(tag) => (tag as i32, 0),
+        };
+        (self.kind(), a, b).encode(out);
+    }
+}
+
+/// A record in the [`History`] log: tag, timestamp and price.
+type Record = (u32, i32, i32);
+
+/// Size of a [`Record`].
+const RECORD_SIZE: usize = 12;
+
+/// One asset's prices, as kept by a [`History`].
+#[derive(Debug)]
+struct SeriesData {
+    prices: Mutex<Prices>,
+    /// Held while an insert is being logged, so inserts into the series
+    /// get logged in the order they're made.
+    logging: tokio::sync::Mutex<()>,
+}
+
+impl SeriesData {
+    fn new(prices: Prices) -> Arc<SeriesData> {
+        Arc::new(SeriesData { prices: Mutex::new(prices), logging: Default::default() })
+    }
+}
+
+/**
+Price series shared between sessions by asset tag, and kept in an
+append-only log so they outlive the server.
+
+Each series follows the same [`Duplicates`] and [`Rounding`] rules as any
+other `Prices`; only inserts that those rules accept, and that change
+something, make it into the log. They're logged before they're applied,
+so nothing's ever in memory that wouldn't be there after a restart.
+*/
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    log: Mutex<File>,
+    series: Mutex<HashMap<u32, Arc<SeriesData>>>,
+    duplicates: Duplicates,
+    rounding: Rounding,
+}
+
+impl History {
+    /// Open (or create) the log at `path`, and read back whatever's in it.
+    ///
+    /// If the server died partway through writing a record, the partial
+    /// record gets dropped.
+    pub fn open(path: &Path, duplicates: Duplicates, rounding: Rounding) -> Result<History, String> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)
+            .map_err(|e| format!("unable to open history log {:?}: {}", path, &e))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| format!("unable to read history log {:?}: {}", path, &e))?;
+
+        let whole = data.len() - data.len() % RECORD_SIZE;
+        if whole < data.len() {
+            log::warn!(
+                "dropping {} bytes of partial record from the end of {:?}",
+                data.len() - whole, path
+            );
+            file.set_len(whole as u64)
+                .map_err(|e| format!("unable to truncate history log {:?}: {}", path, &e))?;
+        }
+
+        let mut series: HashMap<u32, Prices> = HashMap::new();
+        let mut rejected: usize = 0;
+        let mut records = Reader::new(&data[..whole]);
+        while let Ok((tag, timestamp, price)) = records.read::<Record>() {
+            let prices = series.entry(tag)
+                .or_insert_with(|| Prices::with_rules(duplicates, rounding));
+            // Only possible if the log was written under a different policy.
+            if prices.insert(timestamp, price).is_err() {
+                rejected += 1;
+            }
+        }
+        if rejected > 0 {
+            log::warn!("ignored {} duplicate prices in {:?}", rejected, path);
+        }
+        log::info!(
+            "read {} prices for {} assets from {:?}",
+            whole / RECORD_SIZE, series.len(), path
+        );
+
+        let series = series.into_iter()
+            .map(|(tag, prices)| (tag, SeriesData::new(prices)))
+            .collect();
+        Ok(History {
+            path: path.to_path_buf(),
+            log: Mutex::new(file),
+            series: Mutex::new(series),
+            duplicates,
+            rounding,
+        })
+    }
+
+    /// The series for the asset `tag`, which starts out empty if nobody's
+    /// used it before.
+    pub fn series(self: &Arc<Self>, tag: u32) -> Series {
+        let mut series = self.series.lock().unwrap();
+        let data = series.entry(tag)
+            .or_insert_with(|| SeriesData::new(Prices::with_rules(self.duplicates, self.rounding)))
+            .clone();
+        Series { tag, data, history: self.clone() }
+    }
+
+    /// How many prices each series holds, by tag.
+    pub fn sizes(&self) -> Vec<(u32, usize)> {
+        self.series.lock().unwrap().iter()
+            .map(|(&tag, data)| (tag, data.prices.lock().unwrap().len()))
+            .collect()
+    }
+
+    /// Write `record` to the log, off the async runtime.
+    async fn append(self: &Arc<Self>, record: Record) -> Result<(), String> {
+        let history = self.clone();
+        tokio::task::spawn_blocking(move || history.write(record)).await
+            .map_err(|e| format!("unable to write to history log {:?}: {}", &self.path, &e))?
+    }
+
+    fn write(&self, record: Record) -> Result<(), String> {
+        let mut bytes = Vec::with_capacity(RECORD_SIZE);
+        record.encode(&mut bytes);
+        let err = |e: std::io::Error| format!(
+            "unable to write to history log {:?}: {}", &self.path, &e
+        );
+
+        let mut log = self.log.lock().unwrap();
+        let len = log.metadata().map_err(err)?.len();
+        if let Err(e) = log.write_all(&bytes) {
+            // Don't leave part of a record there for the next one to be
+            // written after.
+            let _ = log.set_len(len);
+            return Err(err(e));
+        }
+        Ok(())
+    }
+}
+
+/// One asset's price series, from a [`History`].
+#[derive(Debug, Clone)]
+pub struct Series {
+    tag: u32,
+    data: Arc<SeriesData>,
+    history: Arc<History>,
+}
+
+impl Series {
+    /// Insert a price, if the [`Duplicates`] rules let it in and it changes
+    /// something, logging it first. Returns whether anything changed.
+    pub async fn insert(&self, timestamp: i32, price: i32) -> Result<bool, String> {
+        let _turn = self.data.logging.lock().await;
+        if !self.with(|prices| prices.check(timestamp))? {
+            return Ok(false);
+        }
+        self.apply(timestamp, price).await
+    }
+
+    /// Log a price, then insert it. The caller holds the logging lock and
+    /// has checked that the insert changes something.
+    async fn apply(&self, timestamp: i32, price: i32) -> Result<bool, String> {
+        self.history.append((self.tag, timestamp, price)).await?;
+        self.data.prices.lock().unwrap().insert(timestamp, price)
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(&Prices) -> R) -> R {
+        f(&self.data.prices.lock().unwrap())
+    }
+}
+
+/// How many prices one session is holding, and what its budget has cost it.
+#[derive(Debug, Default)]
+pub struct SessionUsage {
+    pub entries: AtomicUsize,
+    pub peak: AtomicUsize,
+    pub evicted: AtomicUsize,
+    pub rejected: AtomicUsize,
+}
+
+/// How many prices all the sessions, and the shared series, are holding.
+#[derive(Debug, Default)]
+pub struct Usage {
+    total: AtomicUsize,
+    sessions: Mutex<BTreeMap<usize, Arc<SessionUsage>>>,
+    series: Mutex<BTreeMap<u32, usize>>,
+}
+
+impl Usage {
+    /// Start keeping track of client `client_n`'s session.
+    pub fn start(&self, client_n: usize) -> Arc<SessionUsage> {
+        let session = Arc::new(SessionUsage::default());
+        self.sessions.lock().unwrap().insert(client_n, session.clone());
+        session
+    }
+
+    /// Stop keeping track of client `client_n`'s session, whose prices are
+    /// about to be (or have been) dropped.
+    pub fn end(&self, client_n: usize) -> Option<Arc<SessionUsage>> {
+        let session = self.sessions.lock().unwrap().remove(&client_n)?;
+        self.release(session.entries.load(Ordering::Relaxed));
+        Some(session)
+    }
+
+    /// How many prices are held, all told.
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// How many prices each live session holds, by client number.
+    pub fn sessions(&self) -> Vec<(usize, usize)> {
+        self.sessions.lock().unwrap().iter()
+            .map(|(&n, s)| (n, s.entries.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// How many prices each shared series holds, by tag.
+    pub fn series(&self) -> Vec<(u32, usize)> {
+        self.series.lock().unwrap().iter().map(|(&tag, &n)| (tag, n)).collect()
+    }
+
+    /// Count the prices a [`History`] already holds toward the total.
+    pub fn add_history(&self, history: &History) {
+        let mut series = self.series.lock().unwrap();
+        for (tag, n) in history.sizes() {
+            self.total.fetch_add(n, Ordering::Relaxed);
+            series.insert(tag, n);
+        }
+    }
+
+    fn set_series(&self, tag: u32, n: usize) {
+        self.series.lock().unwrap().insert(tag, n);
+    }
+
+    /// Make room for one more price, if that doesn't take the total past
+    /// `max`.
+    fn reserve(&self, max: Option<usize>) -> bool {
+        self.total.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| match max {
+            Some(max) if n >= max => None,
+            _ => Some(n + 1),
+        }).is_ok()
+    }
+
+    fn release(&self, n: usize) {
+        self.total.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+/// Insert a price into a session's own `prices`, keeping within the budgets
+/// in `opts`.
+fn insert_within_budget(
+    prices: &mut Prices,
+    m: Insert,
+    opts: &Options,
+    usage: &Usage,
+    session: &SessionUsage,
+) -> Result<(), String> {
+    if !prices.would_grow(m.timestamp) {
+        prices.insert(m.timestamp, m.price)?;
+        return Ok(());
+    }
+
+    loop {
+        let over = if opts.session_entries().is_some_and(|max| prices.len() >= max) {
+            "session"
+        } else if !usage.reserve(opts.total_entries()) {
+            "total"
+        } else {
+            break;
+        };
+
+        match opts.over_budget {
+            OverBudget::Disconnect => {
+                return Err(format!("insert would go over the {} memory budget", over));
+            },
+            OverBudget::Evict if prices.pop_first().is_some() => {
+                usage.release(1);
+                session.evicted.fetch_add(1, Ordering::Relaxed);
+            },
+            // Rejecting, or nothing left to evict.
+            _ => {
+                session.rejected.fetch_add(1, Ordering::Relaxed);
+                session.entries.store(prices.len(), Ordering::Relaxed);
+                return Ok(());
+            },
+        }
+    }
+
+    // We've checked that this will grow, so it can't be rejected.
+    prices.insert(m.timestamp, m.price)?;
+    session.entries.store(prices.len(), Ordering::Relaxed);
+    session.peak.fetch_max(prices.len(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Insert a price into a shared `series`, keeping within the budgets in
+/// `opts`: the series is held to the session budget, and its prices count
+/// toward the total. Nothing gets evicted from a series, since it's all in
+/// the log anyway.
+async fn insert_shared_within_budget(
+    series: &Series,
+    m: Insert,
+    opts: &Options,
+    usage: &Usage,
+    session: &SessionUsage,
+) -> Result<(), String> {
+    let _turn = series.data.logging.lock().await;
+    let (changes, grows, len) = series.with(|prices| {
+        prices.check(m.timestamp).map(|c| (c, prices.would_grow(m.timestamp), prices.len()))
+    })?;
+    if !changes {
+        return Ok(());
+    }
+
+    if grows {
+        let over = if opts.session_entries().is_some_and(|max| len >= max) {
+            Some("session")
+        } else if !usage.reserve(opts.total_entries()) {
+            Some("total")
+        } else {
+            None
+        };
+        if let Some(over) = over {
+            if opts.over_budget == OverBudget::Disconnect {
+                return Err(format!("insert would go over the {} memory budget", over));
+            }
+            session.rejected.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+    }
+
+    if let Err(e) = series.apply(m.timestamp, m.price).await {
+        if grows {
+            usage.release(1);
+        }
+        return Err(e);
+    }
+    usage.set_series(series.tag, series.with(|prices| prices.len()));
+    Ok(())
+}
+
+/// Where a session keeps its prices.
+enum Store {
+    /// Its own, forgotten when it disconnects.
+    Own(Prices),
+    /// An asset's, shared and logged.
+    Shared(Series),
+}
+
+impl Store {
+    async fn insert(
+        &mut self,
+        m: Insert,
+        opts: &Options,
+        usage: &Usage,
+        session: &SessionUsage,
+    ) -> Result<(), String> {
+        match self {
+            Store::Own(prices) => insert_within_budget(prices, m, opts, usage, session),
+            Store::Shared(series) => {
+                insert_shared_within_budget(series, m, opts, usage, session).await
+            },
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&Prices) -> R) -> R {
+        match self {
+            Store::Own(prices) => f(prices),
+            Store::Shared(series) => series.with(f),
+        }
+    }
+}
+
+/// Add the response to any message that asks a question about `prices` to
+/// `out`. Messages that don't ask anything add nothing.
+fn answer(prices: &Prices, msg: &Msg, width: i32, out: &mut Vec<u8>) -> Result<(), String> {
+    match *msg {
+        Msg::Q(m) => prices.average(m.begin, m.end).encode(out),
+        Msg::Min(m) => {
+            let s = prices.range(m.begin, m.end);
+            let min = if s.count == 0 { 0 } else { s.min };
+            min.encode(out);
+        },
+        Msg::Max(m) => {
+            let s = prices.range(m.begin, m.end);
+            let max = if s.count == 0 { 0 } else { s.max };
+            max.encode(out);
+        },
+        Msg::Median(m) => prices.median(m.begin, m.end).encode(out),
+        Msg::Count(m) => (prices.range(m.begin, m.end).count as i32).encode(out),
+        Msg::Sum(m) => prices.range(m.begin, m.end).sum.encode(out),
+        Msg::Windows(m) => {
+            let n = window_count(m.begin, m.end, width);
+            if n > MAX_WINDOWS {
+                return Err(format!("{} windows is too many (max {})", n, MAX_WINDOWS));
+            }
+            let means = prices.windows(m.begin, m.end, width);
+            out.reserve(4 * (means.len() + 1));
+            (n as i32).encode(out);
+            for mean in means {
+                mean.encode(out);
+            }
+        },
+        Msg::I(_) | Msg::Width(_) | Msg::Tag(_) => {},
+    }
+    Ok(())
+}
+
+/// How much to read from a client at once.
+const READ_SIZE: usize = 64 * 1024;
+
+/// How many bytes of responses to collect before sending them, rather than
+/// waiting until everything that was read has been answered. A `B` query
+/// can add up to 40 KB on top of this.
+const WRITE_SIZE: usize = 64 * 1024;
+
+/// Where one client's session is at.
+struct Session<'a> {
+    opts: &'a Options,
+    usage: &'a Usage,
+    session: &'a SessionUsage,
+    store: Store,
+    width: i32,
+    first: bool,
+}
+
+impl Session<'_> {
+    /// Act on one message, adding any response to `out`.
+    async fn handle(&mut self, msg: Msg, out: &mut Vec<u8>) -> Result<(), String> {
+        let opts = self.opts;
+        let msg = msg.allowed(opts.extensions, opts.history.is_some())?;
+        let was_first = std::mem::replace(&mut self.first, false);
+
+        match msg {
+            Msg::I(m) => {
+                self.store.insert(m, opts, self.usage, self.session).await?;
+            },
+            Msg::Width(w) => {
+                if w < 1 {
+                    return Err(format!("window width must be positive, not {}", w));
+                }
+                self.width = w;
+            },
+            Msg::Tag(tag) => {
+                if !was_first {
+                    return Err("an asset tag has to be the first message".into());
+                }
+                // Only decoded when there's a history.
+                let history = opts.history.as_ref().unwrap();
+                self.store = Store::Shared(history.series(tag));
+                // It's the first message, so there's nothing to release.
+            },
+            msg => {
+                let width = self.width;
+                self.store.with(|prices| answer(prices, &msg, width, out))?;
+            },
+        }
+        Ok(())
+    }
+}
+
+/**
+Serve a single client until it disconnects, sends a bad message, or
+`shutdown`.
+
+Rather than a read per message and a write per response, this reads as much
+as the client has sent (up to `READ_SIZE`), acts on every complete message
+in it, then sends all the responses at once, or sooner if they come to
+more than `WRITE_SIZE`. Any responses to messages before a bad one still
+get sent.
+*/
+pub async fn handler<S: AsyncRead + AsyncWrite + Unpin>(
+    sock: &mut S,
+    opts: &Options,
+    usage: &Usage,
+    session: &SessionUsage,
+    shutdown: &Shutdown,
+) -> Result<(), String> {
+    let mut state = Session {
+        opts,
+        usage,
+        session,
+        store: Store::Own(Prices::with_rules(opts.duplicates, opts.rounding)),
+        width: 1,
+        first: true,
+    };
+    let mut frames: Frames<Msg> = Frames::new();
+    let mut out: Vec<u8> = Vec::new();
+
+    loop {
+        // Losing part of a message here is fine; we're hanging up anyway.
+        let res = tokio::select! {
+            res = frames.read_from(sock, READ_SIZE) => res,
+            _ = shutdown.wait() => { return Ok(()); },
+        };
+        let n_read = res.map_err(|e| format!("Error reading from socket: {}", &e))?;
+
+        // Whatever's left in `frames` is the start of a message that's
+        // still coming.
+        let mut res = Ok(());
+        for msg in frames.by_ref() {
+            res = match msg {
+                Ok(msg) => state.handle(msg, &mut out).await,
+                Err(e) => Err(e),
+            };
+            if res.is_err() { break; }
+            // Sending now keeps `out` (which never shrinks) small, however
+            // much a read's worth of queries asks for.
+            if out.len() >= WRITE_SIZE {
+                send(sock, &mut out).await?;
+            }
+        }
+        send(sock, &mut out).await?;
+        res?;
+
+        if n_read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Send, then forget, any responses in `out`.
+async fn send<S: AsyncWrite + Unpin>(sock: &mut S, out: &mut Vec<u8>) -> Result<(), String> {
+    if !out.is_empty() {
+        sock.write_all(out).await.map_err(|e| format!(
+            "Error writing response to socket: {}", &e
+        ))?;
+        out.clear();
+    }
+    Ok(())
+}
+
+/// Run `handler()`, then log what happened and hang up.
+pub async fn handler_wrapper(
+    mut sock: TcpStream,
+    client_n: usize,
+    opts: Arc<Options>,
+    usage: Arc<Usage>,
+    shutdown: Shutdown,
+) {
+    let session = usage.start(client_n);
+    if let Err(e) = handler(&mut sock, &opts, &usage, &session, &shutdown).await {
+        log::info!("Error handling client {}: {}", client_n, &e);
+    }
+    usage.end(client_n);
+    log::info!(
+        "Client {} held at most {} prices; {} evicted, {} rejected.",
+        client_n,
+        session.peak.load(Ordering::Relaxed),
+        session.evicted.load(Ordering::Relaxed),
+        session.rejected.load(Ordering::Relaxed),
+    );
+    match sock.shutdown().await {
+        Ok(()) => {
+            log::info!("Disconnected from client {}.", client_n);
+        },
+        Err(e) => {
+            log::error!(
+                "Error shutting down socket from client {}: {}",
+                client_n, &e
+            );
+        }
+    }
+}
+
+/// Accept connections on `listener` until `shutdown`, each in its own task.
+pub async fn serve(
+    listener: impl Into<Listeners>,
+    opts: Options,
+    cfg: Config,
+    shutdown: Shutdown,
+) {
+    let listener = listener.into().limit(cfg.max_connections);
+    let opts = Arc::new(opts);
+    let usage = Arc::new(Usage::default());
+    if let Some(history) = &opts.history {
+        usage.add_history(history);
+    }
+    let mut client_n: usize = 0;
+    let mut report = interval(USAGE_INTERVAL);
+    report.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let res = tokio::select! {
+            res = listener.accept() => res,
+            _ = report.tick() => {
+                let sessions = usage.sessions();
+                if let Some((n, most)) = sessions.iter().max_by_key(|(_, entries)| *entries) {
+                    log::info!(
+                        "{} prices held in all; of {} sessions, client {} has the most, with {}.",
+                        usage.total(), sessions.len(), n, most
+                    );
+                }
+                let series = usage.series();
+                if let Some((tag, most)) = series.iter().max_by_key(|(_, entries)| *entries) {
+                    log::info!(
+                        "{} shared series; {:08x} has the most prices, with {}.",
+                        series.len(), tag, most
+                    );
+                }
+                continue;
+            },
+            _ = shutdown.wait() => { break; },
+        };
+        match res {
+            Ok((sock, addr, slot)) => {
+                log::info!("Accepted client {} from {:?}", client_n, &addr);
+                let tracked = shutdown.track(format!("means client {} ({:?})", client_n, &addr));
+                let shutdown = shutdown.clone();
+                let opts = opts.clone();
+                let usage = usage.clone();
+                tokio::spawn(async move {
+                    handler_wrapper(sock, client_n, opts, usage, shutdown).await;
+                    drop((slot, tracked));
+                });
+                client_n += 1;
+            },
+            Err(e) => {
+                log::error!("Error with incoming connection: {}", &e);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_as_btreemap() {
+        let mut prices = Prices::new();
+        let mut map = BTreeMap::new();
+        assert_eq!(prices.average(i32::MIN, i32::MAX), 0);
+
+        // Plenty of repeated timestamps, to check that replacing works.
+        let mut x: u32 = 1;
+        let mut next = || {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            x
+        };
+        for _ in 0..2000 {
+            let timestamp = (next() % 500) as i32 - 250;
+            let price = next() as i32;
+            prices.insert(timestamp, price).unwrap();
+            map.insert(timestamp, price);
+        }
+        prices.insert(i32::MIN, i32::MIN).unwrap();
+        map.insert(i32::MIN, i32::MIN);
+        prices.insert(i32::MAX, i32::MAX).unwrap();
+        map.insert(i32::MAX, i32::MAX);
+        assert_eq!(prices.len(), map.len());
+
+        let mut ranges = vec![
+            (i32::MIN, i32::MAX),
+            (i32::MIN, i32::MIN),
+            (i32::MAX, i32::MAX),
+            (10, -10),
+            (1000, 2000),
+        ];
+        for _ in 0..2000 {
+            let low = (next() % 600) as i32 - 300;
+            ranges.push((low, low + (next() % 300) as i32));
+        }
+        for (low, high) in ranges {
+            assert_eq!(
+                prices.average(low, high),
+                range_average(&map, low, high),
+                "range {}..={}", low, high
+            );
+
+            let mut in_range: Vec<i32> = map.range(low..=high.max(low)).map(|(_, &p)| p).collect();
+            if high < low {
+                in_range.clear();
+            }
+            let s = prices.range(low, high);
+            assert_eq!(s.count as usize, in_range.len());
+            assert_eq!(s.sum, in_range.iter().map(|&p| p as i64).sum::<i64>());
+            if let (Some(&min), Some(&max)) = (in_range.iter().min(), in_range.iter().max()) {
+                assert_eq!((s.min, s.max), (min, max));
+            }
+
+            in_range.sort_unstable();
+            let n = in_range.len();
+            let median = match n {
+                0 => 0,
+                n if n % 2 == 1 => in_range[n / 2],
+                n => ((in_range[n / 2 - 1] as i64 + in_range[n / 2] as i64) / 2) as i32,
+            };
+            assert_eq!(prices.median(low, high), median, "range {}..={}", low, high);
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let mut prices = Prices::new();
+        for (ts, price) in [(1, 10), (2, 20), (3, 30), (5, 50), (9, 90)] {
+            prices.insert(ts, price).unwrap();
+        }
+        assert_eq!(prices.windows(1, 9, 3), vec![20, 50, 90]);
+        assert_eq!(prices.windows(0, 10, 4), vec![20, 50, 90]);
+        assert_eq!(prices.windows(2, 2, 100), vec![20]);
+        assert_eq!(prices.windows(3, 2, 1), Vec::<i32>::new());
+        assert_eq!(window_count(1, 9, 3), 3);
+        assert_eq!(window_count(0, 10, 4), 3);
+        assert_eq!(window_count(3, 2, 1), 0);
+        assert_eq!(window_count(i32::MIN, i32::MAX, 1), 1 << 32);
+
+        // Windows run all the way up to the top without overflowing.
+        prices.insert(i32::MAX, 7).unwrap();
+        assert_eq!(prices.windows(i32::MAX - 1, i32::MAX, 1), vec![0, 7]);
+    }
+
+    /// The obvious, slow way of doing what a `Prices` does.
+    struct Naive {
+        prices: Vec<(i32, i32)>,
+        duplicates: Duplicates,
+        rounding: Rounding,
+    }
+
+    impl Naive {
+        fn insert(&mut self, timestamp: i32, price: i32) -> Result<(), ()> {
+            let existing = self.prices.iter().position(|&(ts, _)| ts == timestamp);
+            match (self.duplicates, existing) {
+                (Duplicates::Reject, Some(_)) => { return Err(()); },
+                (Duplicates::First, Some(_)) => {},
+                (Duplicates::Last, Some(n)) => { self.prices[n].1 = price; },
+                _ => { self.prices.push((timestamp, price)); },
+            }
+            Ok(())
+        }
+
+        fn in_range(&self, low: i32, high: i32) -> Vec<i32> {
+            self.prices.iter()
+                .filter(|&&(ts, _)| low <= ts && ts <= high)
+                .map(|&(_, price)| price)
+                .collect()
+        }
+
+        /// Round `sum / n` by working out the remainder the long way.
+        fn divide(&self, sum: i128, n: i128) -> i32 {
+            let (q, r) = (sum / n, sum % n);
+            let q = match self.rounding {
+                Rounding::Truncate => q,
+                Rounding::Floor if r < 0 => q - 1,
+                Rounding::Floor => q,
+                Rounding::Nearest if 2 * r.abs() >= n => q + sum.signum(),
+                Rounding::Nearest => q,
+            };
+            q as i32
+        }
+
+        fn average(&self, low: i32, high: i32) -> i32 {
+            let prices = self.in_range(low, high);
+            if prices.is_empty() { return 0; }
+            let sum: i128 = prices.iter().map(|&p| p as i128).sum();
+            self.divide(sum, prices.len() as i128)
+        }
+
+        fn median(&self, low: i32, high: i32) -> i32 {
+            let mut prices = self.in_range(low, high);
+            prices.sort_unstable();
+            match prices.len() {
+                0 => 0,
+                n if n % 2 == 1 => prices[n / 2],
+                n => self.divide(prices[n / 2 - 1] as i128 + prices[n / 2] as i128, 2),
+            }
+        }
+    }
+
+    /// Pick from some awkward values most of the time, and anything at all
+    /// the rest.
+    fn awkward(x: u64, pool: &[i32]) -> i32 {
+        let n = (x >> 40) as usize % (pool.len() + 2);
+        match pool.get(n) {
+            Some(&v) => v,
+            None => (x >> 8) as i32,
+        }
+    }
+
+    #[test]
+    fn same_as_naive() {
+        const TIMESTAMPS: &[i32] = &[
+            i32::MIN, i32::MIN + 1, -2, -1, 0, 1, 2, 3, i32::MAX - 1, i32::MAX,
+        ];
+        const PRICES: &[i32] = &[i32::MIN, i32::MIN + 1, -1, 0, 1, i32::MAX - 1, i32::MAX];
+        let policies = [Duplicates::Reject, Duplicates::First, Duplicates::Last, Duplicates::All];
+        let roundings = [Rounding::Truncate, Rounding::Floor, Rounding::Nearest];
+
+        let mut x: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+
+        for duplicates in policies {
+            for rounding in roundings {
+                for _ in 0..50 {
+                    let mut prices = Prices::with_rules(duplicates, rounding);
+                    let mut naive = Naive { prices: Vec::new(), duplicates, rounding };
+                    for _ in 0..100 {
+                        if next() % 3 != 0 {
+                            let ts = awkward(next(), TIMESTAMPS);
+                            let price = awkward(next(), PRICES);
+                            let res = prices.insert(ts, price);
+                            assert_eq!(res.is_err(), naive.insert(ts, price).is_err());
+                            if res.is_err() {
+                                // The client would have been disconnected.
+                                break;
+                            }
+                        } else {
+                            // Half the time, begin > end.
+                            let (low, high) = (awkward(next(), TIMESTAMPS), awkward(next(), TIMESTAMPS));
+                            assert_eq!(
+                                prices.average(low, high), naive.average(low, high),
+                                "{:?} {:?} {}..={} over {:?}",
+                                duplicates, rounding, low, high, &naive.prices
+                            );
+                            assert_eq!(
+                                prices.median(low, high), naive.median(low, high),
+                                "{:?} {:?} {}..={} over {:?}",
+                                duplicates, rounding, low, high, &naive.prices
+                            );
+                        }
+                    }
+                    assert_eq!(prices.len(), naive.prices.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rounding() {
+        for (sum, n, truncate, floor, nearest) in [
+            (7, 2, 3, 3, 4),
+            (-7, 2, -3, -4, -4),
+            (5, 3, 1, 1, 2),
+            (-5, 3, -1, -2, -2),
+            (4, 3, 1, 1, 1),
+            (-4, 3, -1, -2, -1),
+            (6, 3, 2, 2, 2),
+            (i64::MAX, 2, i64::MAX / 2, i64::MAX / 2, i64::MAX / 2 + 1),
+            (i64::MIN, 3, i64::MIN / 3, i64::MIN / 3 - 1, i64::MIN / 3 - 1),
+        ] {
+            assert_eq!(Rounding::Truncate.divide(sum, n), truncate, "{} / {}", sum, n);
+            assert_eq!(Rounding::Floor.divide(sum, n), floor, "{} / {}", sum, n);
+            assert_eq!(Rounding::Nearest.divide(sum, n), nearest, "{} / {}", sum, n);
+        }
+    }
+
+    #[test]
+    fn pop_first() {
+        let mut prices = Prices::new();
+        for (ts, price) in [(5, 50), (1, 10), (3, 30), (2, 20), (4, 40)] {
+            prices.insert(ts, price).unwrap();
+        }
+        assert_eq!(prices.pop_first(), Some((1, 10)));
+        assert_eq!(prices.pop_first(), Some((2, 20)));
+        assert_eq!((prices.len(), prices.average(1, 5)), (3, 40));
+
+        // Freed slots get reused.
+        prices.insert(0, 0).unwrap();
+        prices.insert(6, 60).unwrap();
+        assert_eq!(prices.nodes.len(), 5);
+        assert_eq!((prices.len(), prices.average(0, 6)), (5, 36));
+        assert_eq!(prices.median(0, 6), 40);
+
+        while prices.pop_first().is_some() {}
+        assert!(prices.is_empty());
+        assert_eq!(prices.average(i32::MIN, i32::MAX), 0);
+    }
+
+    #[tokio::test]
+    async fn history_survives() {
+        let path = std::env::temp_dir().join(format!("ph-means-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || Arc::new(History::open(&path, Duplicates::Reject, Rounding::Truncate).unwrap());
+
+        let history = open();
+        let a = history.series(1);
+        a.insert(1, 10).await.unwrap();
+        a.insert(2, 20).await.unwrap();
+        // Everybody with the same tag sees the same prices...
+        history.series(1).insert(3, 30).await.unwrap();
+        assert_eq!(a.with(|p| p.average(1, 3)), 20);
+        // ...and follows the same rules, which keep rejects out of the log.
+        assert!(history.series(1).insert(3, 300).await.is_err());
+        // Other tags are separate.
+        history.series(2).insert(1, -5).await.unwrap();
+        assert_eq!(history.series(2).with(|p| p.len()), 1);
+        drop((a, history));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * RECORD_SIZE as u64);
+
+        // Pretend we died halfway through writing a record.
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0, 0, 0, 1, 0]).unwrap();
+
+        let history = open();
+        assert_eq!(history.series(1).with(|p| (p.len(), p.average(1, 3))), (3, 20));
+        assert_eq!(history.series(2).with(|p| p.average(i32::MIN, i32::MAX)), -5);
+        assert!(history.series(3).with(|p| p.is_empty()));
+        history.series(2).insert(2, -7).await.unwrap();
+        drop(history);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5 * RECORD_SIZE as u64);
+
+        let history = open();
+        assert_eq!(history.series(2).with(|p| p.average(i32::MIN, i32::MAX)), -6);
+        drop(history);
+        std::fs::remove_file(&path).unwrap();
+
+        // Ignored duplicates don't get logged either.
+        let history = Arc::new(History::open(&path, Duplicates::First, Rounding::Truncate).unwrap());
+        assert_eq!(history.series(1).insert(1, 10).await, Ok(true));
+        assert_eq!(history.series(1).insert(1, 20).await, Ok(false));
+        assert_eq!(history.series(1).with(|p| p.average(1, 1)), 10);
+        drop(history);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), RECORD_SIZE as u64);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// A socket that remembers the most it was ever asked to write at once.
+    struct Recorder {
+        inner: tokio::io::DuplexStream,
+        most: usize,
+    }
+
+    impl AsyncRead for Recorder {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.most = self.most.max(buf.len());
+            std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_go_out_as_they_pile_up() {
+        use tokio::io::AsyncReadExt;
+
+        let (client, server) = tokio::io::duplex(READ_SIZE);
+        let server = tokio::spawn(async move {
+            let opts = Options { extensions: true, ..Default::default() };
+            let usage = Usage::default();
+            let session = usage.start(0);
+            let mut sock = Recorder { inner: server, most: 0 };
+            handler(&mut sock, &opts, &usage, &session, &Shutdown::default()).await.unwrap();
+            sock.most
+        });
+
+        // A hundred B 1 10000 queries, each answered with 10,000 means, all
+        // in one read.
+        let n = 100;
+        let mut msgs = Vec::new();
+        for _ in 0..n {
+            Msg::Windows(Query { begin: 1, end: 10_000 }).encode(&mut msgs);
+        }
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer.write_all(&msgs).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut answers = Vec::new();
+        reader.read_to_end(&mut answers).await.unwrap();
+        assert_eq!(answers.len(), n * 4 * 10_001);
+
+        let most = server.await.unwrap();
+        assert!(most < WRITE_SIZE + 4 * 10_001, "wrote {} bytes at once", most);
+    }
+}
@@ -0,0 +1,182 @@
+/*!
+Listening on several addresses at once, with an optional cap on how many
+connections are open at a time.
+*/
+use std::{io, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+
+use futures::future::select_all;
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+/// Held for as long as a connection accepted from [`Listeners`] is being
+/// served; dropping it makes room for another one.
+pub type Slot = Option<OwnedSemaphorePermit>;
+
+/// A cap on how many connections are served at once, which can be shared
+/// between [`Listeners`] and any other way of accepting connections.
+#[derive(Clone, Debug, Default)]
+pub struct Limit(Option<Arc<Semaphore>>);
+
+impl Limit {
+    /// Allow at most `max` connections at once. `None` means no limit.
+    pub fn new(max: Option<usize>) -> Limit {
+        Limit(max.map(|n| Arc::new(Semaphore::new(n))))
+    }
+
+    /// Wait until there's room for another connection.
+    pub async fn acquire(&self) -> Slot {
+        match &self.0 {
+            // The semaphore is never closed.
+            Some(sem) => Some(sem.clone().acquire_owned().await.unwrap()),
+            None => None,
+        }
+    }
+}
+
+/// One or more `TcpListener`s, accepted from as one.
+pub struct Listeners {
+    inner: Vec<TcpListener>,
+    limit: Limit,
+}
+
+impl From<TcpListener> for Listeners {
+    fn from(listener: TcpListener) -> Self {
+        Listeners { inner: vec![listener], limit: Limit::default() }
+    }
+}
+
+impl Listeners {
+    /// Bind a listener to each of `addrs`, of which there must be at least one.
+    pub async fn bind(addrs: &[String]) -> Result<Listeners, String> {
+        if addrs.is_empty() {
+            return Err("no addresses to bind".into());
+        }
+        let mut inner = Vec::with_capacity(addrs.len());
+        for addr in addrs.iter() {
+            let listener = TcpListener::bind(addr).await
+                .map_err(|e| format!("unable to bind {}: {}", addr, &e))?;
+            inner.push(listener);
+        }
+        Ok(Listeners { inner, limit: Limit::default() })
+    }
+
+    /// Allow at most `max` connections to be served at once. `None` means
+    /// no limit.
+    pub fn limit(self, max: Option<usize>) -> Self {
+        self.limit_by(Limit::new(max))
+    }
+
+    /// Count connections against `limit`, which may be shared with
+    /// something else.
+    pub fn limit_by(mut self, limit: Limit) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.inner.iter().filter_map(|l| l.local_addr().ok()).collect()
+    }
+
+    /**
+    Accept a connection on whichever listener gets one first.
+
+    If there's a limit and it's been reached, this waits for a `Slot` to be
+    dropped before accepting anything.
+    */
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr, Slot)> {
+        let slot = self.limit.acquire().await;
+
+        let (sock, addr) = if let [listener] = self.inner.as_slice() {
+            listener.accept().await?
+        } else {
+            let accepts = self.inner.iter().map(|l| Box::pin(l.accept()));
+            select_all(accepts).await.0?
+        };
+
+        Ok((sock, addr, slot))
+    }
+}
+
+/// Bind a `UdpSocket` to the only one of `addrs`. A server that keeps state
+/// across datagrams has every client talk to the same socket, so there must
+/// be exactly one address.
+pub async fn bind_udp(addrs: &[String]) -> Result<UdpSocket, String> {
+    match addrs {
+        [addr] => UdpSocket::bind(addr).await
+            .map_err(|e| format!("unable to bind {}: {}", addr, &e)),
+        _ => Err(format!("can only bind one UDP address, not {}", addrs.len())),
+    }
+}
+
+/**
+Somewhere to listen, for servers that can do more than TCP, written as a
+URI: `tcp://ADDR`, `udp://ADDR`, `unix://PATH` (a Unix stream socket) or
+`unixgram://PATH` (a Unix datagram socket). Anything without a scheme is a
+TCP address.
+*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(String),
+    Udp(String),
+    Unix(PathBuf),
+    UnixDatagram(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match s.split_once("://") {
+            Some((scheme, rest)) => (scheme, rest),
+            None => ("tcp", s),
+        };
+        if rest.is_empty() {
+            return Err(format!("no address or path in {:?}", s));
+        }
+        match scheme {
+            "tcp" => Ok(Endpoint::Tcp(rest.to_string())),
+            "udp" => Ok(Endpoint::Udp(rest.to_string())),
+            "unix" => Ok(Endpoint::Unix(rest.into())),
+            "unixgram" => Ok(Endpoint::UnixDatagram(rest.into())),
+            _ => Err(format!("unknown scheme {:?} in {:?}", scheme, s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn several_with_limit() {
+        let addrs = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let listeners = Listeners::bind(&addrs).await.unwrap().limit(Some(1));
+        let local = listeners.local_addrs();
+        assert_eq!(local.len(), 2);
+
+        let _a = TcpStream::connect(local[1]).await.unwrap();
+        let (_, _, slot) = listeners.accept().await.unwrap();
+        assert!(slot.is_some());
+
+        // The second connection waits until the first one's slot is freed.
+        let _b = TcpStream::connect(local[0]).await.unwrap();
+        assert!(timeout(Duration::from_millis(100), listeners.accept()).await.is_err());
+        drop(slot);
+        assert!(timeout(Duration::from_secs(1), listeners.accept()).await.is_ok());
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!("0.0.0.0:1".parse(), Ok(Endpoint::Tcp("0.0.0.0:1".into())));
+        assert_eq!("tcp://[::1]:2".parse(), Ok(Endpoint::Tcp("[::1]:2".into())));
+        assert_eq!("udp://127.0.0.1:3".parse(), Ok(Endpoint::Udp("127.0.0.1:3".into())));
+        assert_eq!("unix:///tmp/echo".parse(), Ok(Endpoint::Unix("/tmp/echo".into())));
+        assert_eq!("unixgram://echo.sock".parse(), Ok(Endpoint::UnixDatagram("echo.sock".into())));
+        assert!("sctp://127.0.0.1:4".parse::<Endpoint>().is_err());
+        assert!("unix://".parse::<Endpoint>().is_err());
+    }
+}
@@ -2,23 +2,254 @@
 A primality checker, suitable for programs that need to repeatedly check
 integers for primality.
 
-This is not thread-safe, and if used by multiple threads should be
-wrapped in a Mutex.
+Numbers below [`MR_THRESHOLD`] are checked by trial division against an
+internal cache of known primes, which grows as necessary; numbers at or
+above it are checked with a deterministic Miller-Rabin test, so a single
+huge query never has to grow the cache to billions of entries.
+
+Integers too large for a `u64` can be checked with [`is_probable_prime`].
+
+The cache is filled by a segmented Sieve of Eratosthenes, which also backs
+the range queries ([`Primes::primes_in`], [`Primes::count_in`],
+[`Primes::nth`], [`Primes::next_prime`], [`Primes::prev_prime`]).
+
+Composite numbers can be factored with [`Primes::factor`], which also
+backs [`Primes::smallest_factor`], [`Primes::divisor_count`], and
+[`Primes::totient`].
+
+The cache can be saved to disk and loaded back with [`Primes::save`] and
+[`Primes::load`], so long-running programs don't have to start cold.
+
+`Primes` is not thread-safe, and if used by multiple threads should be
+wrapped in a Mutex. Better yet, use [`SharedPrimes`], which checks numbers
+against the cache without locking at all.
+*/
+mod factor;
+mod shared;
+mod sieve;
+mod snapshot;
+
+pub use shared::SharedPrimes;
+pub use sieve::PrimeRange;
+
+use num_bigint::BigUint;
+use num_traits::{One, ToPrimitive, Zero};
+
+/// Numbers at or above this are checked with [`miller_rabin`] instead of
+/// by trial division. Checking anything below this requires caching at most
+/// the primes below 2^16.
+pub const MR_THRESHOLD: u64 = 1 << 32;
+
+/// Testing against the first twelve primes as witnesses is sufficient to
+/// make Miller-Rabin deterministic for every `u64` (it's good up to about
+/// 3.3 * 10^24, in fact).
+const MR_WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Witnesses used by [`is_probable_prime`]; also used for a quick round of
+/// trial division before bothering with any modular exponentiation.
+const BIG_WITNESSES: [u32; 24] = [
+     2,  3,  5,  7, 11, 13, 17, 19, 23, 29, 31, 37,
+    41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89,
+];
+
+// Return `a * b mod m` without overflowing.
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    ((a as u128 * b as u128) % m as u128) as u64
+}
+
+// Return `base^exp mod m`.
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut acc: u64 = 1 % m;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = mul_mod(acc, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    acc
+}
+
+/**
+Deterministic Miller-Rabin primality test, good for the entire `u64` range.
+
+This doesn't touch any cache, so it's safe to call from anywhere.
 */
+pub fn miller_rabin(n: u64) -> bool {
+    if n < 2 { return false; }
+    for &p in MR_WITNESSES.iter() {
+        if n == p {
+            return true;
+        } else if n.is_multiple_of(p) {
+            return false;
+        }
+    }
+
+    // Write n - 1 as d * 2^s with d odd.
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+
+    'witness: for &a in MR_WITNESSES.iter() {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+
+    true
+}
+
+/// `x` as a residue mod `n`.
+fn residue(x: i64, n: &BigUint) -> BigUint {
+    let abs = BigUint::from(x.unsigned_abs()) % n;
+    if x < 0 && !abs.is_zero() { n - abs } else { abs }
+}
 
-// Return an upper bound on the square root of `n`.
-fn sqrt_sup(n: u64) -> u64 {
-    let x = n as f64;
-    x.sqrt().ceil() as u64
+/// The Jacobi symbol (`a`/`n`), for odd `n`.
+fn jacobi(a: &BigUint, n: &BigUint) -> i32 {
+    let low = |x: &BigUint| x.iter_u32_digits().next().unwrap_or(0);
+    let (mut a, mut n) = (a % n, n.clone());
+    let mut t = 1;
+    while !a.is_zero() {
+        // This unwrapping is fine because `a` is nonzero.
+        let z = a.trailing_zeros().unwrap();
+        a >>= z;
+        if z % 2 == 1 && matches!(low(&n) % 8, 3 | 5) {
+            t = -t;
+        }
+        std::mem::swap(&mut a, &mut n);
+        if low(&a) % 4 == 3 && low(&n) % 4 == 3 {
+            t = -t;
+        }
+        a %= &n;
+    }
+    if n.is_one() { t } else { 0 }
 }
 
+/**
+Strong Lucas probable prime test, with parameters chosen by Selfridge's
+method: `D` is the first of 5, -7, 9, -11, ... with (`D`/`n`) = -1, `P` is
+1 and `Q` is (1 - `D`) / 4.
+
+`n` must be odd and bigger than any `D` this gets to (anything that's
+survived [`is_probable_prime`]'s trial division will do).
+*/
+fn strong_lucas(n: &BigUint) -> bool {
+    // A square would have us looking for D forever.
+    let root = n.sqrt();
+    if &root * &root == *n {
+        return false;
+    }
+
+    let mut d: i64 = 5;
+    loop {
+        match jacobi(&residue(d, n), n) {
+            -1 => break,
+            0 => return false,
+            _ => { d = if d > 0 { -d - 2 } else { -d + 2 }; },
+        }
+    }
+    let d_mod = residue(d, n);
+    let q_mod = residue((1 - d) / 4, n);
+
+    // Write n + 1 as k * 2^s with k odd.
+    let n_plus_one = n + 1u32;
+    // This unwrapping is fine because `n + 1` is nonzero.
+    let s = n_plus_one.trailing_zeros().unwrap();
+    let k = &n_plus_one >> s;
+
+    // Work out U_k, V_k and Q^k a bit at a time, from U_1 = V_1 = P = 1.
+    let half = |x: BigUint| if x.bit(0) { (x + n) >> 1 } else { x >> 1 } % n;
+    let double = |v: &BigUint, qk: &BigUint| (v * v + (n - qk) * 2u32) % n;
+    let (mut u, mut v, mut qk) = (BigUint::one(), BigUint::one(), q_mod.clone());
+    for i in (0..k.bits() - 1).rev() {
+        u = &u * &v % n;
+        v = double(&v, &qk);
+        qk = &qk * &qk % n;
+        if k.bit(i) {
+            (u, v) = (half(&u + &v), half(&d_mod * &u + &v));
+            qk = &qk * &q_mod % n;
+        }
+    }
+
+    if u.is_zero() || v.is_zero() {
+        return true;
+    }
+    for _ in 1..s {
+        v = double(&v, &qk);
+        if v.is_zero() {
+            return true;
+        }
+        qk = &qk * &qk % n;
+    }
+    false
+}
+
+/**
+Baillie-PSW primality test for arbitrarily large integers.
+
+Anything that fits in a `u64` is handed off to [`miller_rabin`], so the
+answer is exact there. Beyond that, this is Miller-Rabin against a fixed
+set of 24 prime witnesses followed by a strong Lucas test. A `false` is
+always correct. A fixed set of witnesses on its own can be fooled by a
+number built for the purpose, but no composite is known that passes both
+tests, even though they're thought to exist.
+
+The cost grows roughly with the cube of the number of digits, so callers
+taking input from strangers should put a limit on the size of `n`.
+*/
+pub fn is_probable_prime(n: &BigUint) -> bool {
+    if let Some(n) = n.to_u64() {
+        return miller_rabin(n);
+    }
+
+    for &p in BIG_WITNESSES.iter() {
+        if (n % p).is_zero() {
+            return false;
+        }
+    }
+
+    let one = BigUint::one();
+    let n_minus_one = n - &one;
+    // This unwrapping is fine because `n - 1` is nonzero.
+    let s = n_minus_one.trailing_zeros().unwrap();
+    let d = &n_minus_one >> s;
+
+    'witness: for &a in BIG_WITNESSES.iter() {
+        let mut x = BigUint::from(a).modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = &x * &x % n;
+            if x == n_minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+
+    strong_lucas(n)
+}
+
+#[derive(Clone)]
 pub struct Primes {
-    known: Vec<u64>
+    known: Vec<u64>,
+    // Every prime less than or equal to this is in `known`.
+    limit: u64,
 }
 
 impl Default for Primes {
     fn default() -> Self {
-        Self { known: vec![2] }
+        Self { known: vec![2], limit: 2 }
     }
 }
 
@@ -31,51 +262,32 @@ impl Primes {
         let mut known: Vec<u64> = Vec::with_capacity(cap);
         known.push(2);
 
-        Self { known }
+        Self { known, limit: 2 }
     }
 
-    // The last element of `self.known` *must* exceed `sqrt_n_sup` before
-    // calling. `sqrt_n_sup` must be equal to or greater than `sqrt(n). 
-    fn check(&self, n: u64, sqrt_n_sup: u64) -> bool {
+    // `self.limit` *must* be at least `sqrt_n` before calling, and
+    // `sqrt_n` must be equal to or greater than `sqrt(n)`.
+    fn check(&self, n: u64, sqrt_n: u64) -> bool {
         for &p in self.known.iter() {
-            if p > sqrt_n_sup {
+            if p > sqrt_n || n == p {
                 return true;
-            } else if n == p {
-                return true;
-            }else if n % p == 0 {
+            } else if n.is_multiple_of(p) {
                 return false;
             }
         }
-        // For maximum Rustiness, we should panic! here, instead of silently
-        // returning success.
-        return true
-    }
-
-    fn append_next(&mut self) {
-        // This unwrapping should be fine because all public constructors
-        // ensure `self.known` has at least one element.
-        let mut next = *self.known.last().unwrap() + 1;
-
-        loop {
-            let next_sqrt_sup = sqrt_sup(next);
-            if self.check(next, next_sqrt_sup) {
-                self.known.push(next);
-                return;
-            }
-            next += 1;
-        }
+        // We've run out of known primes, but they include every prime up
+        // to `sqrt_n`, so none of them divide `n`.
+        true
     }
 
     pub fn is_prime(&mut self, n: u64) -> bool {
         if n < 2 { return false; }
+        if n >= MR_THRESHOLD { return miller_rabin(n); }
 
-        let sqrt_n_sup = sqrt_sup(n);
+        let sqrt_n = n.isqrt();
+        self.extend_to(sqrt_n);
 
-        while *self.known.last().unwrap() < sqrt_n_sup {
-            self.append_next();
-        }
-
-        self.check(n, sqrt_n_sup)
+        self.check(n, sqrt_n)
     }
 }
 
@@ -91,4 +303,69 @@ mod test {
             println!("{}: {}", n, pz.is_prime(n));
         }
     }
+
+    #[test]
+    fn miller_rabin_agrees_with_trial_division() {
+        let mut pz = Primes::default();
+
+        for n in 0..100_000 {
+            assert_eq!(pz.is_prime(n), miller_rabin(n), "disagreement at {}", n);
+        }
+    }
+
+    #[test]
+    fn miller_rabin_large() {
+        let mut pz = Primes::default();
+
+        // The largest prime below 2^64, and the smallest above 2^32.
+        assert!(pz.is_prime(18_446_744_073_709_551_557));
+        assert!(pz.is_prime(4_294_967_311));
+        assert!(!pz.is_prime(u64::MAX));
+        // Product of two primes just under 2^32.
+        assert!(!pz.is_prime(4_294_967_291 * 4_294_967_279));
+        // Strong pseudoprimes to several small bases.
+        assert!(!pz.is_prime(3_215_031_751));
+        assert!(!pz.is_prime(2_152_302_898_747));
+        assert!(!pz.is_prime(3_825_123_056_546_413_051));
+
+        // None of that should have grown the cache past sqrt(MR_THRESHOLD).
+        assert!(*pz.known.last().unwrap() < 65_600);
+    }
+
+    #[test]
+    fn big_primes() {
+        let big = |s: &str| BigUint::parse_bytes(s.as_bytes(), 10).unwrap();
+
+        // 2^89 - 1 and 2^127 - 1 are Mersenne primes.
+        assert!(is_probable_prime(&big("618970019642690137449562111")));
+        assert!(is_probable_prime(&big("170141183460469231731687303715884105727")));
+        // 2^67 - 1 famously isn't.
+        assert!(!is_probable_prime(&big("147573952589676412927")));
+        // Product of two 20-digit primes.
+        assert!(!is_probable_prime(
+            &(big("10000000000000000051") * big("10000000000000000087"))
+        ));
+        // Small values should agree with the deterministic test.
+        for n in 0..10_000u64 {
+            assert_eq!(is_probable_prime(&BigUint::from(n)), miller_rabin(n));
+        }
+    }
+
+    #[test]
+    fn strong_lucas_pseudoprimes() {
+        // The only composites below 100,000 that pass (OEIS A217255); none
+        // of them are strong pseudoprimes to base 2, which is the point.
+        let fooled: Vec<u64> = (101..100_000u64).step_by(2)
+            .filter(|&n| strong_lucas(&BigUint::from(n)) != miller_rabin(n))
+            .collect();
+        assert_eq!(
+            fooled,
+            vec![5459, 5777, 10877, 16109, 18971, 22499, 24569, 25199, 40309, 58519, 75077, 97439]
+        );
+        for &n in fooled.iter() {
+            assert!(!miller_rabin(n));
+        }
+        assert_eq!(jacobi(&BigUint::from(5u32), &BigUint::from(21u32)), 1);
+        assert_eq!(jacobi(&residue(-1, &BigUint::from(7u32)), &BigUint::from(7u32)), -1);
+    }
 }
\ No newline at end of file
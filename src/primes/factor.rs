@@ -0,0 +1,231 @@
+/*!
+Integer factorization.
+
+Small factors are found by trial division against the cached primes below
+`TRIAL_LIMIT`. Whatever's left over has only large prime factors; it gets
+split with Pollard's rho (using Brent's cycle detection) until every piece
+passes `miller_rabin()`.
+*/
+use super::{miller_rabin, mul_mod, Primes, SharedPrimes};
+
+/// Trial division uses the primes below this. Anything left over after
+/// that that's less than `TRIAL_LIMIT` squared must be prime.
+const TRIAL_LIMIT: u64 = 1 << 16;
+/// Number of steps Brent's rho takes between gcd calculations.
+const RHO_BATCH: u64 = 128;
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Try to find a nontrivial factor of the odd composite `n` with Pollard's
+/// rho, iterating `x -> x^2 + c`. Returns `None` if this `c` fails.
+fn brent(n: u64, c: u64) -> Option<u64> {
+    let f = |x: u64| ((mul_mod(x, x, n) as u128 + c as u128) % n as u128) as u64;
+
+    let (mut x, mut y, mut ys) = (2u64, 2u64, 2u64);
+    let (mut q, mut g, mut r) = (1u64, 1u64, 1u64);
+
+    while g == 1 {
+        x = y;
+        for _ in 0..r {
+            y = f(y);
+        }
+        let mut k = 0;
+        while k < r && g == 1 {
+            ys = y;
+            for _ in 0..RHO_BATCH.min(r - k) {
+                y = f(y);
+                q = mul_mod(q, x.abs_diff(y), n);
+            }
+            g = gcd(q, n);
+            k += RHO_BATCH;
+        }
+        r *= 2;
+    }
+
+    // The batched product hit zero; back up and go one step at a time.
+    if g == n {
+        loop {
+            ys = f(ys);
+            g = gcd(x.abs_diff(ys), n);
+            if g > 1 { break; }
+        }
+    }
+
+    if g == n { None } else { Some(g) }
+}
+
+/// Push the prime factors of `n`, none of which are small, onto `out`.
+fn split(n: u64, out: &mut Vec<u64>) {
+    if n == 1 { return; }
+    if n < TRIAL_LIMIT * TRIAL_LIMIT || miller_rabin(n) {
+        out.push(n);
+        return;
+    }
+
+    // Rho has a hard time with perfect squares.
+    let root = n.isqrt();
+    if root * root == n {
+        split(root, out);
+        split(root, out);
+        return;
+    }
+
+    // This will always find something before `c` gets very big at all.
+    let d = (1..).find_map(|c| brent(n, c)).unwrap();
+    split(d, out);
+    split(n / d, out);
+}
+
+/// Factor `n`, using `small` (which must yield every prime below
+/// `TRIAL_LIMIT` in increasing order) for trial division.
+fn factorize<I: Iterator<Item = u64>>(small: I, mut n: u64) -> Vec<(u64, u32)> {
+    let mut factors: Vec<(u64, u32)> = Vec::new();
+    if n < 2 { return factors; }
+
+    for p in small.take_while(|&p| p < TRIAL_LIMIT) {
+        if p * p > n { break; }
+        let mut exp = 0;
+        while n.is_multiple_of(p) {
+            n /= p;
+            exp += 1;
+        }
+        if exp > 0 {
+            factors.push((p, exp));
+        }
+    }
+
+    let mut large: Vec<u64> = Vec::new();
+    split(n, &mut large);
+    large.sort_unstable();
+    for p in large {
+        match factors.last_mut() {
+            Some((q, exp)) if *q == p => { *exp += 1; },
+            _ => { factors.push((p, 1)); },
+        }
+    }
+
+    factors
+}
+
+/// Number of divisors of the number with the given prime factorization.
+fn divisors_from(factors: &[(u64, u32)]) -> u64 {
+    factors.iter().map(|&(_, e)| e as u64 + 1).product()
+}
+
+/// Euler's totient of the number with the given prime factorization.
+fn totient_from(factors: &[(u64, u32)]) -> u64 {
+    factors.iter()
+        .map(|&(p, e)| (p - 1) * p.pow(e - 1))
+        .product()
+}
+
+impl Primes {
+    /**
+    Return the prime factorization of `n` as `(prime, exponent)` pairs in
+    increasing order of prime.
+
+    The factorizations of 0 and 1 are empty.
+
+    ```
+    let mut pz = ph::primes::Primes::default();
+    assert_eq!(pz.factor(360), vec![(2, 3), (3, 2), (5, 1)]);
+    ```
+    */
+    pub fn factor(&mut self, n: u64) -> Vec<(u64, u32)> {
+        self.extend_to(TRIAL_LIMIT);
+        factorize(self.known.iter().copied(), n)
+    }
+
+    /// Return the smallest prime factor of `n`, or `None` if `n < 2`.
+    pub fn smallest_factor(&mut self, n: u64) -> Option<u64> {
+        self.factor(n).first().map(|&(p, _)| p)
+    }
+
+    /// Return the number of divisors of `n` (including 1 and `n`). For
+    /// lack of any better answer, this returns 0 for 0.
+    pub fn divisor_count(&mut self, n: u64) -> u64 {
+        if n == 0 { return 0; }
+        divisors_from(&self.factor(n))
+    }
+
+    /// Return Euler's totient of `n`: the number of integers in `1..=n`
+    /// coprime to `n`.
+    pub fn totient(&mut self, n: u64) -> u64 {
+        if n == 0 { return 0; }
+        totient_from(&self.factor(n))
+    }
+}
+
+impl SharedPrimes {
+    /// Return the prime factorization of `n`; see [`Primes::factor`].
+    pub fn factor(&self, n: u64) -> Vec<(u64, u32)> {
+        self.grow_to(TRIAL_LIMIT);
+        factorize(self.known(), n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn product(factors: &[(u64, u32)]) -> u64 {
+        factors.iter().map(|&(p, e)| p.pow(e)).product()
+    }
+
+    #[test]
+    fn factors() {
+        let mut pz = Primes::default();
+
+        assert_eq!(pz.factor(0), vec![]);
+        assert_eq!(pz.factor(1), vec![]);
+        assert_eq!(pz.factor(2), vec![(2, 1)]);
+        assert_eq!(pz.factor(1 << 63), vec![(2, 63)]);
+        assert_eq!(
+            pz.factor(u64::MAX),
+            vec![(3, 1), (5, 1), (17, 1), (257, 1), (641, 1), (65_537, 1), (6_700_417, 1)]
+        );
+        assert_eq!(
+            pz.factor(4_294_967_291 * 4_294_967_279),
+            vec![(4_294_967_279, 1), (4_294_967_291, 1)]
+        );
+        assert_eq!(pz.factor(4_294_967_291 * 4_294_967_291), vec![(4_294_967_291, 2)]);
+        assert_eq!(
+            pz.factor(18_446_744_073_709_551_557),
+            vec![(18_446_744_073_709_551_557, 1)]
+        );
+        // Two medium factors, and a small one.
+        assert_eq!(
+            pz.factor(3 * 1_000_003 * 999_999_000_001),
+            vec![(3, 1), (1_000_003, 1), (999_999_000_001, 1)]
+        );
+
+        for n in (1..u64::MAX).step_by(1_234_567_890_123_457).take(2_000) {
+            let f = pz.factor(n);
+            assert_eq!(product(&f), n);
+            assert!(f.iter().all(|&(p, _)| miller_rabin(p)), "{}: {:?}", n, &f);
+        }
+
+        let shared = SharedPrimes::default();
+        assert_eq!(shared.factor(360), pz.factor(360));
+    }
+
+    #[test]
+    fn derived() {
+        let mut pz = Primes::default();
+
+        assert_eq!(pz.smallest_factor(1), None);
+        assert_eq!(pz.smallest_factor(91), Some(7));
+        assert_eq!(pz.smallest_factor(4_294_967_291 * 4_294_967_279), Some(4_294_967_279));
+        assert_eq!(pz.divisor_count(1), 1);
+        assert_eq!(pz.divisor_count(36), 9);
+        assert_eq!(pz.divisor_count(97), 2);
+        assert_eq!(pz.totient(1), 1);
+        assert_eq!(pz.totient(36), 12);
+        assert_eq!(pz.totient(97), 96);
+    }
+}
@@ -0,0 +1,231 @@
+/*!
+A primality checker that can be shared between threads.
+
+The cache is published in an append-only structure of geometrically
+growing segments, so checking a number against primes that are already
+known never takes a lock. Only growing the cache does, and then only
+against other threads that are also trying to grow it.
+*/
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Mutex, OnceLock,
+};
+
+use super::{
+    miller_rabin,
+    sieve::{base_limit, count_uncached},
+    Primes, MR_THRESHOLD,
+};
+
+/// Length of the first segment; each one after that is twice as long as
+/// the one before.
+const FIRST_SEGMENT: usize = 1024;
+/// This many segments could hold more primes than there are below 2^64.
+const N_SEGMENTS: usize = 48;
+
+// Return the segment and offset within that segment of the `n`th entry.
+fn locate(n: usize) -> (usize, usize) {
+    let k = (n / FIRST_SEGMENT + 1).ilog2() as usize;
+    let before = FIRST_SEGMENT * ((1 << k) - 1);
+    (k, n - before)
+}
+
+/**
+Thread-safe counterpart of [`Primes`].
+
+```
+use ph::primes::SharedPrimes;
+use std::sync::Arc;
+
+let pz = Arc::new(SharedPrimes::default());
+let handles: Vec<_> = (0..4).map(|_| {
+    let pz = pz.clone();
+    std::thread::spawn(move || pz.is_prime(1_000_003))
+}).collect();
+
+for h in handles {
+    assert!(h.join().unwrap());
+}
+```
+*/
+pub struct SharedPrimes {
+    segments: [OnceLock<Box<[AtomicU64]>>; N_SEGMENTS],
+    // Number of published primes. Every entry below this index has been
+    // written before this is stored.
+    len: AtomicUsize,
+    // Every prime less than or equal to this has been published. This is
+    // stored after `len`.
+    limit: AtomicU64,
+    // The writer's own contiguous copy of the cache, which does the actual
+    // sieving. Only touched when the cache needs to grow.
+    writer: Mutex<Primes>,
+}
+
+impl Default for SharedPrimes {
+    fn default() -> Self {
+        SharedPrimes::from(Primes::default())
+    }
+}
+
+impl From<Primes> for SharedPrimes {
+    fn from(primes: Primes) -> Self {
+        let shared = SharedPrimes {
+            segments: [const { OnceLock::new() }; N_SEGMENTS],
+            len: AtomicUsize::new(0),
+            limit: AtomicU64::new(0),
+            writer: Mutex::new(primes),
+        };
+        // This unwrapping is fine; nobody else can have the lock yet.
+        shared.publish(&shared.writer.lock().unwrap());
+        shared
+    }
+}
+
+impl SharedPrimes {
+    // Copy any entries of `primes` past the published length into the
+    // segments, then publish them. Must only be called with the `writer`
+    // lock held (which is where `primes` comes from).
+    fn publish(&self, primes: &Primes) {
+        let old_len = self.len.load(Ordering::Relaxed);
+        for (n, &p) in primes.known.iter().enumerate().skip(old_len) {
+            let (k, offset) = locate(n);
+            let seg = self.segments[k].get_or_init(|| {
+                (0..(FIRST_SEGMENT << k)).map(|_| AtomicU64::new(0)).collect()
+            });
+            seg[offset].store(p, Ordering::Relaxed);
+        }
+        self.len.store(primes.known.len(), Ordering::Release);
+        self.limit.store(primes.limit, Ordering::Release);
+    }
+
+    // Ensure every prime up to and including `limit` has been published.
+    pub(super) fn grow_to(&self, limit: u64) {
+        if self.limit.load(Ordering::Acquire) >= limit { return; }
+
+        // If a thread panicked while holding the lock, the worst it can have
+        // done is leave `known` partially extended, which is still correct.
+        let mut primes = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if primes.limit < limit {
+            primes.extend_to(limit);
+            self.publish(&primes);
+        }
+    }
+
+    /// Iterate over the published primes, in order.
+    pub(super) fn known(&self) -> impl Iterator<Item = u64> + '_ {
+        let len = self.len.load(Ordering::Acquire);
+        self.segments.iter()
+            .map_while(|seg| seg.get())
+            .flat_map(|seg| seg.iter())
+            .take(len)
+            .map(|p| p.load(Ordering::Relaxed))
+    }
+
+    /// Number of primes in the cache.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// Whether the cache is empty (which it never is).
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return a copy of the cache as an ordinary [`Primes`].
+    pub fn to_primes(&self) -> Primes {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn is_prime(&self, n: u64) -> bool {
+        if n < 2 { return false; }
+        if n >= MR_THRESHOLD { return miller_rabin(n); }
+
+        let sqrt_n = n.isqrt();
+        self.grow_to(sqrt_n);
+
+        for p in self.known() {
+            if p > sqrt_n || p == n {
+                return true;
+            } else if n.is_multiple_of(p) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return the smallest prime greater than `n`, or `None` if there
+    /// isn't one in the `u64` range.
+    pub fn next_prime(&self, n: u64) -> Option<u64> {
+        (n.checked_add(1)?..=u64::MAX).find(|&m| self.is_prime(m))
+    }
+
+    /**
+    Return the number of primes `p` with `lo <= p < hi`.
+
+    Unlike [`Primes::count_in`], this only adds the primes up to the square
+    root of `hi` to the cache; the range itself is sieved a segment at a
+    time and forgotten, without holding the lock. That can still take a
+    while for a big range, so don't call it from async code.
+    */
+    pub fn count_in(&self, lo: u64, hi: u64) -> u64 {
+        if lo >= hi { return 0; }
+
+        let root = base_limit(hi);
+        self.grow_to(root);
+        let base: Vec<u64> = self.known().take_while(|&p| p <= root).collect();
+        count_uncached(&base, lo, hi)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn segment_math() {
+        assert_eq!(locate(0), (0, 0));
+        assert_eq!(locate(FIRST_SEGMENT - 1), (0, FIRST_SEGMENT - 1));
+        assert_eq!(locate(FIRST_SEGMENT), (1, 0));
+        assert_eq!(locate(3 * FIRST_SEGMENT - 1), (1, 2 * FIRST_SEGMENT - 1));
+        assert_eq!(locate(3 * FIRST_SEGMENT), (2, 0));
+    }
+
+    #[test]
+    fn concurrent_agrees_with_primes() {
+        let shared = Arc::new(SharedPrimes::default());
+
+        let handles: Vec<_> = (0..8u64).map(|t| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let mut pz = Primes::default();
+                // Each thread asks about ever-larger numbers, so they all
+                // race each other to grow the cache.
+                for n in (t..2_000_000_000).step_by(9_999_991) {
+                    assert_eq!(shared.is_prime(n), pz.is_prime(n), "{}", n);
+                }
+            })
+        }).collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let known: Vec<u64> = shared.known().collect();
+        assert_eq!(known, shared.to_primes().known);
+
+        assert_eq!(shared.next_prime(7_919), Some(7_927));
+        assert_eq!(shared.count_in(0, 1_000_000), 78_498);
+        assert_eq!(shared.known().count(), shared.len());
+    }
+
+    #[test]
+    fn counting_leaves_the_cache_alone() {
+        let shared = SharedPrimes::default();
+        assert_eq!(shared.count_in(0, 1 << 24), 1_077_871);
+        assert_eq!(shared.count_in(1 << 24, 1 << 24), 0);
+        // Only the primes up to 2^12 were needed, so only they got cached.
+        assert!(shared.to_primes().limit < 1 << 13);
+        assert_eq!(shared.count_in(1_000, 1_000_000), 78_498 - 168);
+    }
+}
@@ -0,0 +1,290 @@
+/*!
+A segmented Sieve of Eratosthenes, and the range queries on `Primes` that
+are built on it.
+
+Ranges below [`CACHE_LIMIT`] get added to the `Primes` cache as they're
+sieved, so asking about them again is just a binary search. Ranges above
+it are sieved a segment at a time using cached primes up to the square root
+of the top of the range, and then forgotten.
+*/
+use std::{slice, vec};
+
+use super::{miller_rabin, Primes};
+
+/// Width (in integers) of the chunks that get sieved at once.
+const SEGMENT: u64 = 1 << 18;
+/// Range queries extend the cache to cover any part of the range below
+/// this. That's about 3.9 million primes, or about 31 MiB of cache.
+pub const CACHE_LIMIT: u64 = 1 << 26;
+/// Sieving numbers above this would require caching more than a million
+/// base primes, so segments up there are checked one number at a time with
+/// `miller_rabin()` instead.
+const SIEVE_MAX: u64 = 1 << 48;
+
+/// Push every prime in `[lo, hi)` onto `out`.
+///
+/// Unless `hi` exceeds `SIEVE_MAX`, `base` must contain every prime up to
+/// and including `sqrt(hi - 1)`.
+fn sieve_segment(base: &[u64], lo: u64, hi: u64, out: &mut Vec<u64>) {
+    let lo = lo.max(2);
+    if lo >= hi { return; }
+
+    if hi > SIEVE_MAX {
+        out.extend((lo..hi).filter(|&n| miller_rabin(n)));
+        return;
+    }
+
+    let mut composite = vec![false; (hi - lo) as usize];
+    for &p in base.iter() {
+        let mut m = p * p;
+        if m >= hi { break; }
+        if m < lo {
+            m = lo.div_ceil(p) * p;
+        }
+        while m < hi {
+            composite[(m - lo) as usize] = true;
+            m += p;
+        }
+    }
+
+    out.extend(
+        composite.iter().zip(lo..hi)
+            .filter(|(&c, _)| !c)
+            .map(|(_, n)| n)
+    );
+}
+
+/**
+Iterator over the primes in a range, in increasing order, returned by
+[`Primes::primes_in`].
+
+The part of the range that's in the cache is read straight from it; the
+rest is sieved a segment at a time as the iterator advances.
+*/
+pub struct PrimeRange<'a> {
+    cached: slice::Iter<'a, u64>,
+    base: &'a [u64],
+    // Start of the part of the range that hasn't been sieved yet.
+    lo: u64,
+    hi: u64,
+    segment: vec::IntoIter<u64>,
+}
+
+impl PrimeRange<'_> {
+    // Sieve the next segment of the range, returning `false` if there's
+    // nothing left to sieve.
+    fn advance(&mut self) -> bool {
+        if self.lo >= self.hi { return false; }
+
+        let seg_hi = self.lo.saturating_add(SEGMENT).min(self.hi);
+        let mut found = Vec::new();
+        sieve_segment(self.base, self.lo, seg_hi, &mut found);
+        self.segment = found.into_iter();
+        self.lo = seg_hi;
+        true
+    }
+}
+
+impl Iterator for PrimeRange<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if let Some(&p) = self.cached.next() {
+            return Some(p);
+        }
+        loop {
+            if let Some(p) = self.segment.next() {
+                return Some(p);
+            }
+            if !self.advance() {
+                return None;
+            }
+        }
+    }
+
+    fn count(mut self) -> usize {
+        let mut n = self.cached.len() + self.segment.len();
+        while self.advance() {
+            n += self.segment.len();
+        }
+        n
+    }
+}
+
+/// Return the number of primes `p` with `lo <= p < hi`, sieving the whole
+/// range a segment at a time without caching any of it.
+///
+/// As with `sieve_segment()`, `base` must contain every prime up to and
+/// including `sqrt(hi - 1)`, at least as far as `SIEVE_MAX`.
+pub(super) fn count_uncached(base: &[u64], lo: u64, hi: u64) -> u64 {
+    let range = PrimeRange {
+        cached: [].iter(),
+        base,
+        lo,
+        hi,
+        segment: Vec::new().into_iter(),
+    };
+    range.count() as u64
+}
+
+/// The largest number whose square root `count_uncached()` needs primes up
+/// to, when counting up to `hi`.
+pub(super) fn base_limit(hi: u64) -> u64 {
+    (hi.clamp(1, SIEVE_MAX) - 1).isqrt()
+}
+
+impl Primes {
+    /// Ensure the cache contains every prime up to and including `limit`.
+    pub(super) fn extend_to(&mut self, limit: u64) {
+        if limit <= self.limit { return; }
+
+        // Sieving the new segments requires all the primes up to their
+        // square roots.
+        self.extend_to(limit.isqrt());
+
+        let mut found = Vec::new();
+        let mut lo = self.limit + 1;
+        while lo <= limit {
+            let hi = lo.saturating_add(SEGMENT).min(limit.saturating_add(1));
+            sieve_segment(&self.known, lo, hi, &mut found);
+            self.known.append(&mut found);
+            self.limit = hi - 1;
+            lo = hi;
+        }
+    }
+
+    /**
+    Iterate over all the primes `p` with `lo <= p < hi`.
+
+    Any part of the range below [`CACHE_LIMIT`] is added to the cache.
+    */
+    pub fn primes_in(&mut self, lo: u64, hi: u64) -> PrimeRange<'_> {
+        let cache_top = hi.min(CACHE_LIMIT);
+        if lo < cache_top {
+            self.extend_to(cache_top - 1);
+        }
+        let sieve_top = hi.min(SIEVE_MAX);
+        if lo < sieve_top {
+            self.extend_to((sieve_top - 1).isqrt());
+        }
+
+        let start = self.known.partition_point(|&p| p < lo);
+        let end = self.known.partition_point(|&p| p < hi);
+        let (start, end) = (start, end.max(start));
+
+        PrimeRange {
+            cached: self.known[start..end].iter(),
+            base: &self.known,
+            lo: lo.max(self.limit + 1),
+            hi,
+            segment: Vec::new().into_iter(),
+        }
+    }
+
+    /// Return the number of primes `p` with `lo <= p < hi`.
+    pub fn count_in(&mut self, lo: u64, hi: u64) -> u64 {
+        self.primes_in(lo, hi).count() as u64
+    }
+
+    /**
+    Return the `n`th prime, counting from zero (so `nth(0)` is 2), in the
+    same manner as `Iterator::nth()`.
+
+    This extends the cache to contain at least `n + 1` primes, regardless
+    of [`CACHE_LIMIT`].
+    */
+    pub fn nth(&mut self, n: usize) -> u64 {
+        if n >= self.known.len() {
+            // For k >= 6, the kth prime is less than k(ln k + ln ln k).
+            let k = (n + 1).max(6) as f64;
+            let bound = k * (k.ln() + k.ln().ln());
+            self.extend_to(bound as u64);
+        }
+        // The bound above is good, but don't trust the floating-point
+        // arithmetic too far.
+        while n >= self.known.len() {
+            self.extend_to(self.limit.saturating_mul(2));
+        }
+
+        self.known[n]
+    }
+
+    /// Return the smallest prime greater than `n`, or `None` if there
+    /// isn't one in the `u64` range.
+    pub fn next_prime(&self, n: u64) -> Option<u64> {
+        let idx = self.known.partition_point(|&p| p <= n);
+        if let Some(&p) = self.known.get(idx) {
+            return Some(p);
+        }
+
+        let start = n.max(self.limit).checked_add(1)?;
+        (start..=u64::MAX).find(|&m| miller_rabin(m))
+    }
+
+    /// Return the largest prime less than `n`, or `None` if `n <= 2`.
+    pub fn prev_prime(&self, n: u64) -> Option<u64> {
+        if n > self.limit + 1 {
+            if let Some(p) = (self.limit + 1..n).rev().find(|&m| miller_rabin(m)) {
+                return Some(p);
+            }
+        }
+
+        let idx = self.known.partition_point(|&p| p < n);
+        idx.checked_sub(1).map(|i| self.known[i])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let mut pz = Primes::default();
+
+        assert_eq!(
+            pz.primes_in(0, 30).collect::<Vec<_>>(),
+            vec![2, 3, 5, 7, 11, 13, 17, 19, 23, 29]
+        );
+        assert_eq!(pz.primes_in(24, 29).count(), 0);
+        assert_eq!(pz.primes_in(30, 10).count(), 0);
+        assert_eq!(pz.count_in(0, 1_000_000), 78_498);
+        assert_eq!(pz.count_in(999_000, 1_000_000), 65);
+
+        // Straddle the cache limit, and compare against `is_prime()`.
+        let (lo, hi) = (CACHE_LIMIT - 5_000, CACHE_LIMIT + 5_000);
+        let sieved: Vec<u64> = pz.primes_in(lo, hi).collect();
+        let checked: Vec<u64> = (lo..hi).filter(|&n| pz.is_prime(n)).collect();
+        assert_eq!(sieved, checked);
+        assert!(pz.limit < CACHE_LIMIT);
+
+        // Up where sieving gives way to Miller-Rabin.
+        let top: Vec<u64> = pz.primes_in(u64::MAX - 100, u64::MAX).collect();
+        assert_eq!(top, vec![
+            18_446_744_073_709_551_521,
+            18_446_744_073_709_551_533,
+            18_446_744_073_709_551_557,
+        ]);
+    }
+
+    #[test]
+    fn nth_next_prev() {
+        let mut pz = Primes::default();
+
+        assert_eq!(pz.nth(0), 2);
+        assert_eq!(pz.nth(999), 7_919);
+        assert_eq!(pz.nth(78_497), 999_983);
+
+        assert_eq!(pz.next_prime(0), Some(2));
+        assert_eq!(pz.next_prime(2), Some(3));
+        assert_eq!(pz.next_prime(7_919), Some(7_927));
+        assert_eq!(pz.next_prime(999_983), Some(1_000_003));
+        assert_eq!(pz.next_prime(18_446_744_073_709_551_557), None);
+
+        assert_eq!(pz.prev_prime(2), None);
+        assert_eq!(pz.prev_prime(3), Some(2));
+        assert_eq!(pz.prev_prime(7_927), Some(7_919));
+        assert_eq!(pz.prev_prime(1_000_003), Some(999_983));
+        assert_eq!(pz.prev_prime(u64::MAX), Some(18_446_744_073_709_551_557));
+    }
+}
@@ -0,0 +1,263 @@
+/*!
+Saving the `Primes` cache to disk and loading it back.
+
+A snapshot looks like this; all fixed-width integers are little-endian:
+
+| bytes  | contents                                                 |
+|--------|----------------------------------------------------------|
+| 8      | magic: `PHPRIMES`                                        |
+| 1      | format version (currently 1)                             |
+| 8      | `limit`: every prime up to this is in the snapshot       |
+| 8      | `count`: number of primes in the snapshot                |
+| varies | `count - 1` LEB128 varints (see below)                   |
+| 8      | FNV-1a hash of everything before it                      |
+
+The 2 is implied. Every prime after that is odd, so each is stored as half
+its gap from the previous odd prime (starting from 1), which keeps almost
+every entry in a single byte.
+*/
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use super::Primes;
+
+const MAGIC: &[u8; 8] = b"PHPRIMES";
+const VERSION: u8 = 1;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad snapshot: {}", msg))
+}
+
+/// Wraps a writer or reader and hashes every byte that passes through.
+struct Hashing<T> {
+    inner: T,
+    hash: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, hash: FNV_OFFSET }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.hash ^= b as u64;
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+impl<W: Write> Hashing<W> {
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn put_varint(&mut self, mut n: u64) -> io::Result<()> {
+        let mut buff = [0u8; 10];
+        let mut len = 0;
+        loop {
+            let b = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                buff[len] = b;
+                len += 1;
+                break;
+            }
+            buff[len] = b | 0x80;
+            len += 1;
+        }
+        self.put(&buff[..len])
+    }
+}
+
+impl<R: Read> Hashing<R> {
+    fn get<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buff = [0u8; N];
+        self.inner.read_exact(&mut buff)?;
+        self.update(&buff);
+        Ok(buff)
+    }
+
+    fn get_varint(&mut self) -> io::Result<u64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let [b] = self.get::<1>()?;
+            n |= ((b & 0x7f) as u64).checked_shl(shift)
+                .filter(|x| x >> shift == (b & 0x7f) as u64)
+                .ok_or_else(|| invalid("varint overflow"))?;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+}
+
+impl Primes {
+    /// Write a snapshot of the cache to `w`.
+    pub fn write_snapshot<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut w = Hashing::new(w);
+        w.put(MAGIC)?;
+        w.put(&[VERSION])?;
+        w.put(&self.limit.to_le_bytes())?;
+        w.put(&(self.known.len() as u64).to_le_bytes())?;
+
+        let mut prev: u64 = 1;
+        for &p in self.known[1..].iter() {
+            w.put_varint((p - prev) / 2)?;
+            prev = p;
+        }
+
+        let hash = w.hash;
+        w.inner.write_all(&hash.to_le_bytes())?;
+        w.inner.flush()
+    }
+
+    /// Read a snapshot written by [`Primes::write_snapshot`].
+    pub fn read_snapshot<R: Read>(r: R) -> io::Result<Primes> {
+        let mut r = Hashing::new(r);
+        if &r.get::<8>()? != MAGIC {
+            return Err(invalid("not a prime cache snapshot"));
+        }
+        let [version] = r.get::<1>()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let limit = u64::from_le_bytes(r.get()?);
+        let count = u64::from_le_bytes(r.get()?);
+        if count == 0 {
+            return Err(invalid("empty"));
+        }
+
+        // Don't trust `count` too far when pre-allocating.
+        let mut known: Vec<u64> = Vec::with_capacity(count.min(1 << 20) as usize);
+        known.push(2);
+        let mut prev: u64 = 1;
+        for _ in 1..count {
+            let p = r.get_varint()?
+                .checked_mul(2)
+                .and_then(|gap| prev.checked_add(gap))
+                .filter(|&p| p > prev)
+                .ok_or_else(|| invalid("bad gap"))?;
+            known.push(p);
+            prev = p;
+        }
+
+        let hash = r.hash;
+        let mut stored = [0u8; 8];
+        r.inner.read_exact(&mut stored)?;
+        if u64::from_le_bytes(stored) != hash {
+            return Err(invalid("checksum mismatch"));
+        }
+        if known.last().is_some_and(|&p| p > limit) {
+            return Err(invalid("primes exceed limit"));
+        }
+
+        Ok(Primes { known, limit })
+    }
+
+    /**
+    Save a snapshot to the file at `path`.
+
+    The snapshot is written to a temporary file alongside `path`, synced to
+    disk, and then renamed over it, so a crash (or a power cut) mid-write
+    never leaves a truncated snapshot.
+    */
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        self.write_snapshot(&mut w)?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, path)?;
+
+        // Make sure the rename itself sticks. (Directories can only be
+        // opened like this on Unix.)
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Load a snapshot from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Primes> {
+        Primes::read_snapshot(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut pz = Primes::default();
+        pz.extend_to(2_000_000);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        pz.write_snapshot(&mut bytes).unwrap();
+        // Header, checksum, and about one byte per prime.
+        assert!(bytes.len() < 25 + 8 + pz.known.len() + 100);
+
+        let loaded = Primes::read_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(loaded.known, pz.known);
+        assert_eq!(loaded.limit, pz.limit);
+
+        let fresh = Primes::read_snapshot({
+            let mut b = Vec::new();
+            Primes::default().write_snapshot(&mut b).unwrap();
+            b
+        }.as_slice()).unwrap();
+        assert_eq!(fresh.known, vec![2]);
+    }
+
+    #[test]
+    fn corruption() {
+        let mut pz = Primes::default();
+        pz.extend_to(10_000);
+        let mut bytes: Vec<u8> = Vec::new();
+        pz.write_snapshot(&mut bytes).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[100] ^= 0x01;
+        assert!(Primes::read_snapshot(flipped.as_slice()).is_err());
+
+        let truncated = &bytes[..bytes.len() - 3];
+        assert!(Primes::read_snapshot(truncated).is_err());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(Primes::read_snapshot(wrong_magic.as_slice()).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut pz = Primes::default();
+        pz.extend_to(10_000);
+        let path = std::env::temp_dir().join(format!("ph-snapshot-{}", std::process::id()));
+        pz.save(&path).unwrap();
+        // Saving again replaces it, leaving nothing else behind.
+        pz.save(&path).unwrap();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        assert!(!Path::new(&tmp).exists());
+
+        let loaded = Primes::load(&path).unwrap();
+        assert_eq!(loaded.known, pz.known);
+        fs::remove_file(&path).unwrap();
+    }
+}
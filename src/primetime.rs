@@ -0,0 +1,709 @@
+/*!
+Protohackers Problem 1: Prime Time
+
+Officials have devised a JSON-based request-response protocol. Each request
+is a single line containing a JSON object, terminated by a newline character
+('\n', or ASCII 10). Each request begets a response, which is also a single
+line containing a JSON object, terminated by a newline character.
+
+After connecting, a client may send multiple requests in a single session.
+Each request should be handled in order.
+
+A conforming request object has the required field method, which must always
+contain the string "isPrime", and the required field number, which must
+contain a number. Any JSON number is a valid number, including floating-point
+values.
+
+Example request:
+
+```json
+{"method":"isPrime","number":123}
+```
+
+Extraneous fields are to be ignored.
+
+A conforming response object has the required field method, which must always
+contain the string "isPrime", and the required field prime, which must
+contain a boolean value: true if the number in the request was prime, false
+if it was not.
+
+Example response:
+
+```json
+{"method":"isPrime","prime":false}
+```
+
+Accept TCP connections.
+
+Whenever you receive a conforming request, send back a correct response, and
+wait for another request.
+
+Whenever you receive a malformed request, send back a single malformed
+response, and disconnect the client.
+
+Make sure you can handle at least 5 simultaneous clients.
+
+As an extension, this server also accepts these methods, whose number must
+be an integer in the range 0..2^64:
+
+  * "factor" (number must be positive) responds with the field factors,
+    which contains a list of `[prime, exponent]` pairs
+  * "nextPrime" responds with the field prime, which contains the smallest
+    prime greater than number
+  * "primeCount" responds with the field count, which contains the number
+    of primes less than or equal to number
+
+```json
+{"method":"factor","number":360}
+{"method":"factor","factors":[[2,3],[3,2],[5,1]]}
+```
+*/
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::OnceCell;
+use num_bigint::BigUint;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{Config, Opt},
+    net::Listeners,
+    primes::{is_probable_prime, Primes, SharedPrimes},
+    shutdown::Shutdown,
+};
+
+/// Default for the `--max-digits` option: integers with more digits than
+/// this get a malformed response instead of a primality test.
+pub const MAX_DIGITS: usize = 1000;
+/// This server's own options, in addition to the common ones.
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "max-digits", arg: Some("N"), many: false,
+        help: "integers with more than N digits get a malformed response (default 1000)",
+    },
+    Opt {
+        name: "snapshot", arg: Some("PATH"), many: false,
+        help: "load the prime cache from PATH on startup, and save it back there periodically",
+    },
+];
+/// Sent in response to a malformed request, right before disconnecting.
+pub const MALFORMED: &[u8] = b"{\"error\":\"malformed request\"}\n";
+/// Largest number the "primeCount" method will count primes up to. This
+/// takes a few seconds to sieve, but doesn't grow the cache.
+const MAX_COUNT: u64 = 1 << 30;
+
+/// After a malformed request, how long to keep reading (and discarding)
+/// whatever else the client sent, so closing the socket doesn't reset the
+/// connection before the client reads the response.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to save the prime cache, if `--snapshot` was given and the
+/// cache has grown since the last save.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+static PRIMES: OnceCell<SharedPrimes> = OnceCell::new();
+
+/// What a JSON number turns out to be, as far as primality is concerned.
+#[derive(Debug, PartialEq)]
+enum Candidate {
+    /// Negative, or not an integer; definitely not prime.
+    Nope,
+    Small(u64),
+    Big(BigUint),
+}
+
+// Parse the (optionally signed) decimal exponent of a JSON number, saturating
+// at a value big enough to blow past any sane digit limit.
+fn parse_exponent(exp: &str) -> i64 {
+    const CAP: i64 = i64::MAX / 4;
+    let (neg, digits) = match exp.as_bytes().first() {
+        Some(b'-') => (true, &exp[1..]),
+        Some(b'+') => (false, &exp[1..]),
+        _ => (false, exp),
+    };
+    let mag = digits.bytes().fold(0i64, |acc, b| {
+        acc.saturating_mul(10).saturating_add((b - b'0') as i64).min(CAP)
+    });
+    if neg { -mag } else { mag }
+}
+
+/**
+Figure out whether the text of a JSON number represents a nonnegative integer,
+however it's written (`7`, `7.0`, `0.07e2`, `7E0`...).
+
+Returns an error if the integer would have more than `max_digits` digits,
+so nobody can make us test a ten-thousand-digit number.
+*/
+fn candidate(text: &str, max_digits: usize) -> Result<Candidate, String> {
+    let (neg, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let (mantissa, exp) = match text.find(['e', 'E']) {
+        Some(n) => (&text[..n], parse_exponent(&text[(n+1)..])),
+        None => (text, 0),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(n) => (&mantissa[..n], &mantissa[(n+1)..]),
+        None => (mantissa, ""),
+    };
+
+    // All the significant digits, and how many of them come before the
+    // decimal point.
+    let digits = format!("{}{}", int_part, frac_part);
+    let digits = digits.trim_start_matches('0');
+    let point = exp + (int_part.len() as i64) - (
+        (int_part.len() + frac_part.len() - digits.len()) as i64
+    );
+    let digits = digits.trim_end_matches('0');
+
+    if digits.is_empty() {
+        return Ok(Candidate::Small(0));
+    }
+    if neg || (digits.len() as i64) > point {
+        return Ok(Candidate::Nope);
+    }
+    if point > max_digits as i64 {
+        return Err(format!(
+            "number has {} digits (limit is {})", point, max_digits
+        ));
+    }
+
+    let mut int_text = String::with_capacity(point as usize);
+    int_text.push_str(digits);
+    int_text.extend(std::iter::repeat_n('0', point as usize - digits.len()));
+
+    match int_text.parse::<u64>() {
+        Ok(n) => Ok(Candidate::Small(n)),
+        // This unwrapping is safe because `int_text` is all decimal digits.
+        Err(_) => Ok(Candidate::Big(
+            BigUint::parse_bytes(int_text.as_bytes(), 10).unwrap()
+        )),
+    }
+}
+
+fn primes() -> &'static SharedPrimes {
+    PRIMES.get_or_init(SharedPrimes::default)
+}
+
+/// Load the prime cache from the snapshot at `path` (if there is one), then
+/// save it back there every `SNAPSHOT_INTERVAL` that it grows.
+pub fn warm_start(path: PathBuf) {
+    match Primes::load(&path) {
+        Ok(primes) => {
+            let shared = SharedPrimes::from(primes);
+            log::info!("Loaded {} primes from {}", shared.len(), path.display());
+            // If this fails, it's because someone already started using the
+            // cache, which is fine; they just don't get the head start.
+            let _ = PRIMES.set(shared);
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::info!("No snapshot at {}; starting cold.", path.display());
+        },
+        Err(e) => {
+            log::warn!("Error loading snapshot {}: {}", path.display(), &e);
+        },
+    }
+
+    tokio::spawn(async move {
+        let mut saved_len = primes().len();
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let len = primes().len();
+            if len == saved_len { continue; }
+
+            let path = path.clone();
+            let res = tokio::task::spawn_blocking(move ||
+                primes().to_primes().save(&path)
+            ).await;
+            match res {
+                Ok(Ok(())) => {
+                    log::debug!("Saved {} primes to snapshot.", len);
+                    saved_len = len;
+                },
+                Ok(Err(e)) => { log::error!("Error saving snapshot: {}", &e); },
+                Err(e) => { log::error!("Error saving snapshot: {}", &e); },
+            }
+        }
+    });
+}
+
+/// Return the number as a `u64`, or an error if it isn't one.
+fn small_integer(number: &Number, max_digits: usize) -> Result<u64, String> {
+    match candidate(&number.to_string(), max_digits)? {
+        Candidate::Small(n) => Ok(n),
+        _ => Err(format!("{} is not an integer in the range 0..2^64", number)),
+    }
+}
+
+/// Fields shared by every request; the rest depend on the method.
+#[derive(Deserialize)]
+struct Head {
+    method: String,
+}
+
+/// Request for any of the methods that take a single number.
+#[derive(Deserialize, Debug)]
+struct NumberReq {
+    number: Number,
+}
+
+#[derive(Serialize)]
+struct IsPrimeResp {
+    prime: bool,
+}
+
+#[derive(Serialize)]
+struct FactorResp {
+    factors: Vec<(u64, u32)>,
+}
+
+#[derive(Serialize)]
+struct NextPrimeResp {
+    prime: u64,
+}
+
+#[derive(Serialize)]
+struct PrimeCountResp {
+    count: u64,
+}
+
+/// Type-erased method handler: takes the whole request object, and returns
+/// the fields of the response other than `method`.
+type Handler = Box<dyn Fn(Value) -> Result<Map<String, Value>, String> + Send + Sync>;
+
+/// The methods the server knows how to answer, by name.
+#[derive(Default)]
+pub struct Registry {
+    methods: HashMap<&'static str, Handler>,
+}
+
+impl Registry {
+    /**
+    Add a method called `name`.
+
+    The request gets deserialized into a `Q` (so that's the schema it must
+    match; extraneous fields are ignored) and passed to `handler`. Whatever
+    the handler returns must serialize to a JSON object; it becomes the
+    response, with the `method` field added.
+    */
+    pub fn register<Q, R, F>(&mut self, name: &'static str, handler: F) -> &mut Self
+    where
+        Q: DeserializeOwned,
+        R: Serialize,
+        F: Fn(Q) -> Result<R, String> + Send + Sync + 'static,
+    {
+        let erased = move |req: Value| {
+            let req: Q = serde_json::from_value(req).map_err(|e| format!(
+                "Bad {:?} request: {}", name, &e
+            ))?;
+            match serde_json::to_value(handler(req)?) {
+                Ok(Value::Object(fields)) => Ok(fields),
+                Ok(x) => Err(format!("{:?} response isn't an object: {}", name, &x)),
+                Err(e) => Err(format!("Error serializing {:?} response: {}", name, &e)),
+            }
+        };
+        self.methods.insert(name, Box::new(erased));
+        self
+    }
+
+    /// Answer a single request. An error means the request was malformed.
+    pub fn dispatch(&self, bytes: &[u8]) -> Result<Value, String> {
+        let req: Value = serde_json::from_slice(bytes).map_err(|e| format!(
+            "Request couldn't be deserialized: {}", &e
+        ))?;
+        let head = Head::deserialize(&req).map_err(|e| format!(
+            "Request has no method: {}", &e
+        ))?;
+        let handler = self.methods.get(head.method.as_str()).ok_or_else(||
+            format!("Unrecognized method: {:?}", &head.method)
+        )?;
+
+        log::debug!("Rec'd request: {}", &req);
+
+        let mut resp = Map::new();
+        resp.insert("method".into(), Value::String(head.method));
+        resp.extend(handler(req)?);
+        Ok(Value::Object(resp))
+    }
+}
+
+/// All the methods this server supports.
+pub fn registry(max_digits: usize) -> Registry {
+    let mut reg = Registry::default();
+
+    reg.register("isPrime", move |req: NumberReq| {
+        let prime = match candidate(&req.number.to_string(), max_digits)? {
+            Candidate::Nope => false,
+            Candidate::Small(n) => primes().is_prime(n),
+            Candidate::Big(n) => is_probable_prime(&n),
+        };
+        log::debug!("{} ? {}", &req.number, prime);
+        Ok(IsPrimeResp { prime })
+    }).register("factor", move |req: NumberReq| {
+        let n = small_integer(&req.number, max_digits)?;
+        if n == 0 {
+            return Err("Can't factor 0".into());
+        }
+        Ok(FactorResp { factors: primes().factor(n) })
+    }).register("nextPrime", move |req: NumberReq| {
+        let n = small_integer(&req.number, max_digits)?;
+        match primes().next_prime(n) {
+            Some(prime) => Ok(NextPrimeResp { prime }),
+            None => Err(format!("No prime after {} fits in 64 bits", n)),
+        }
+    }).register("primeCount", move |req: NumberReq| {
+        let n = small_integer(&req.number, max_digits)?;
+        if n > MAX_COUNT {
+            return Err(format!("Won't count primes up to {} (limit is {})", n, MAX_COUNT));
+        }
+        Ok(PrimeCountResp { count: primes().count_in(0, n + 1) })
+    });
+
+    reg
+}
+
+/// What `handle()` should do after a chunk of input has been processed.
+enum Next {
+    /// Keep reading; here's the partial line left over from this chunk.
+    Continue(Vec<u8>),
+    /// A malformed request was answered; hang up.
+    Disconnect,
+}
+
+async fn copy_and_process(
+    from: &[u8],
+    mut buff: Vec<u8>,
+    sock: &mut TcpStream,
+    methods: &Arc<Registry>,
+) -> Result <Next, String> {
+    for b in from.iter() {
+        let b = *b;
+        if b == b'\n' {
+            let mut req_buff: Vec<u8> = Vec::new();
+            std::mem::swap(&mut buff, &mut req_buff);
+            // Some methods (like counting primes) can take a while, so
+            // they don't get to hold up everybody else on this thread.
+            let methods = methods.clone();
+            let res = tokio::task::spawn_blocking(move || methods.dispatch(&req_buff)).await
+                .map_err(|e| format!("Error answering request: {}", &e))?;
+            match res {
+                Ok(val) => {
+                    let resp = format!("{}\n", &val);
+                    sock.write_all(resp.as_bytes()).await.map_err(|e| format!(
+                        "Error writing response: {}", &e
+                    ))?;
+                },
+                Err(e) => {
+                    log::warn!("{}", &e);
+                    // Anything else the client sent is forfeit.
+                    sock.write_all(MALFORMED).await.map_err(|e| format!(
+                        "Error writing malformed response: {}", &e
+                    ))?;
+                    sock.flush().await.map_err(|e| format!(
+                        "Error flushing socket: {}", &e
+                    ))?;
+                    return Ok(Next::Disconnect);
+                },
+            }
+        } else {
+            buff.push(b);
+        }
+    }
+
+    Ok(Next::Continue(buff))
+}
+
+/// Close the write half of `sock`, then discard input until the client
+/// closes its side too, or `DRAIN_TIMEOUT`.
+async fn hang_up(sock: &mut TcpStream, client_n: usize) {
+    if let Err(e) = sock.shutdown().await {
+        log::warn!("Error shutting down socket from client {}: {}", client_n, &e);
+        return;
+    }
+    let mut discard = [0u8; 4096];
+    let drain = async {
+        while let Ok(n) = sock.read(&mut discard).await {
+            if n == 0 { break; }
+        }
+    };
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, drain).await;
+}
+
+/// Serve a single client until it disconnects, sends a malformed request,
+/// or `shutdown` (at which point any request it's only sent part of gets
+/// dropped).
+pub async fn handle(
+    mut sock: TcpStream,
+    client_n: usize,
+    methods: Arc<Registry>,
+    buffsize: usize,
+    shutdown: Shutdown,
+) -> usize {
+    let mut readbuff = vec![0u8; buffsize];
+
+    let mut buff: Vec<u8> = Vec::new();
+
+    loop {
+        let res = tokio::select! {
+            res = sock.read(&mut readbuff) => res,
+            _ = shutdown.wait() => {
+                log::info!("Closing connection {} for shutdown.", client_n);
+                break;
+            },
+        };
+        match res {
+            Ok(0) => { break; },
+            Ok(n) => {
+                let res = copy_and_process(&readbuff[..n], buff, &mut sock, &methods).await;
+                match res {
+                    Ok(Next::Continue(new_buff)) => { buff = new_buff; },
+                    Ok(Next::Disconnect) => {
+                        log::info!("Client {} sent a malformed request.", client_n);
+                        hang_up(&mut sock, client_n).await;
+                        return client_n;
+                    },
+                    Err(e) => {
+                        log::warn!("{}", &e);
+                        break;
+                    }
+                }
+            },
+            Err(e) => {
+                if e.kind() == ErrorKind::WouldBlock {
+                    continue;
+                } else {
+                    log::error!("Error reading from socket: {}", &e);
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Err(e) = sock.shutdown().await {
+        eprintln!("Error shutting down socket: {}", &e);
+    }
+
+    client_n
+}
+
+/// Accept connections on `listener` until `shutdown`, answering requests
+/// with `methods`.
+pub async fn serve(
+    listener: impl Into<Listeners>,
+    methods: Arc<Registry>,
+    cfg: Config,
+    shutdown: Shutdown,
+) {
+    let listener = listener.into().limit(cfg.max_connections);
+    let buffsize = cfg.buffer_size;
+    let mut client_n: usize = 0;
+
+    loop {
+        let res = tokio::select! {
+            res = listener.accept() => res,
+            _ = shutdown.wait() => { break; },
+        };
+        match res {
+            Ok((sock, addr, slot)) => {
+                println!("Accepted #{} from {:?}", client_n, &addr);
+                let methods = methods.clone();
+                let tracked = shutdown.track(format!("prime client {} ({:?})", client_n, &addr));
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    let n = handle(sock, client_n, methods, buffsize, shutdown).await;
+                    drop((slot, tracked));
+                    n
+                });
+                client_n += 1;
+            },
+            Err(e) => {
+                eprintln!("Error with incoming connection: {}", &e);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::BUFFER_SIZE;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    fn cand(text: &str) -> Candidate {
+        candidate(text, MAX_DIGITS).unwrap()
+    }
+
+    #[test]
+    fn candidates() {
+        assert_eq!(cand("97"), Candidate::Small(97));
+        assert_eq!(cand("97.000"), Candidate::Small(97));
+        assert_eq!(cand("1e3"), Candidate::Small(1000));
+        assert_eq!(cand("0.097E3"), Candidate::Small(97));
+        assert_eq!(cand("9700e-2"), Candidate::Small(97));
+        assert_eq!(cand("18446744073709551615"), Candidate::Small(u64::MAX));
+        assert_eq!(
+            cand("18446744073709551616"),
+            Candidate::Big(BigUint::from(u64::MAX) + 1u32)
+        );
+        assert_eq!(
+            cand("1.2089258196146292e24"),
+            Candidate::Big(BigUint::parse_bytes(b"1208925819614629200000000", 10).unwrap())
+        );
+
+        for text in ["0", "-0", "0.0", "0e99999999999999999999"] {
+            assert_eq!(cand(text), Candidate::Small(0), "{}", text);
+        }
+        for text in ["-97", "97.5", "-1e3", "9.7", "97e-1", "1e-99999999999999999999"] {
+            assert_eq!(cand(text), Candidate::Nope, "{}", text);
+        }
+
+        assert!(candidate("1e1000", MAX_DIGITS).is_err());
+        assert!(candidate("1e99999999999999999999", MAX_DIGITS).is_err());
+        assert!(candidate("1e999", MAX_DIGITS).is_ok());
+    }
+
+    fn ask(req: &[u8]) -> Result<Value, String> {
+        registry(MAX_DIGITS).dispatch(req)
+    }
+
+    fn prime(is_prime: bool) -> Value {
+        json!({ "method": "isPrime", "prime": is_prime })
+    }
+
+    #[test]
+    fn big_requests() {
+        // 2^89 - 1 is prime; 2^89 + 1 isn't.
+        let req = br#"{"method":"isPrime","number":618970019642690137449562111}"#;
+        assert_eq!(ask(req), Ok(prime(true)));
+        let req = br#"{"method":"isPrime","number":618970019642690137449562113}"#;
+        assert_eq!(ask(req), Ok(prime(false)));
+        let req = br#"{"method":"isPrime","number":6.18970019642690137449562111e26}"#;
+        assert_eq!(ask(req), Ok(prime(true)));
+        let req = br#"{"method":"isPrime","number":"97"}"#;
+        assert!(ask(req).is_err());
+        let req = br#"{"method":"isPrime","number":1e2000}"#;
+        assert!(ask(req).is_err());
+    }
+
+    #[test]
+    fn other_methods() {
+        assert_eq!(
+            ask(br#"{"method":"nextPrime","number":7919,"extra":[1,2]}"#),
+            Ok(json!({ "method": "nextPrime", "prime": 7927 }))
+        );
+        assert_eq!(
+            ask(br#"{"method":"primeCount","number":1e6}"#),
+            Ok(json!({ "method": "primeCount", "count": 78498 }))
+        );
+        assert!(ask(br#"{"method":"nextPrime","number":18446744073709551557}"#).is_err());
+        assert!(ask(br#"{"method":"primeCount","number":1e12}"#).is_err());
+        assert!(ask(br#"{"method":"isComposite","number":12}"#).is_err());
+        assert!(ask(br#"{"method":12,"number":12}"#).is_err());
+        assert!(ask(br#"{"number":12}"#).is_err());
+        assert!(ask(br#"["isPrime", 12]"#).is_err());
+    }
+
+    #[test]
+    fn factor_requests() {
+        let req = br#"{"method":"factor","number":360}"#;
+        assert_eq!(
+            ask(req),
+            Ok(json!({ "method": "factor", "factors": [[2, 3], [3, 2], [5, 1]] }))
+        );
+        let req = br#"{"method":"factor","number":1}"#;
+        assert_eq!(
+            ask(req),
+            Ok(json!({ "method": "factor", "factors": [] }))
+        );
+        for n in ["0", "-6", "2.5", "18446744073709551616"] {
+            let req = format!(r#"{{"method":"factor","number":{}}}"#, n);
+            assert!(ask(req.as_bytes()).is_err(), "{}", n);
+        }
+    }
+
+    /// Serve a single connection from a `handle()` task, and return the
+    /// client end of it.
+    async fn connect() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            handle(
+                sock, 0, Arc::new(registry(MAX_DIGITS)), BUFFER_SIZE, Shutdown::default()
+            ).await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    /// Send `input` all at once and return everything received before the
+    /// server hangs up.
+    async fn transcript(input: &[u8]) -> String {
+        let mut sock = connect().await;
+        sock.write_all(input).await.unwrap();
+        let mut output = String::new();
+        sock.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn fixture() {
+        let output = transcript(include_bytes!("../tests/01_prime.json")).await;
+        assert_eq!(output, include_str!("../tests/01_prime.expected"));
+    }
+
+    #[tokio::test]
+    async fn malformed_disconnects() {
+        let output = transcript(concat!(
+            r#"{"method":"isPrime","number":7}"#, "\n",
+            r#"{"method":"isPrime","number":"7"}"#, "\n",
+            r#"{"method":"isPrime","number":11}"#, "\n",
+        ).as_bytes()).await;
+        assert_eq!(output, concat!(
+            r#"{"method":"isPrime","prime":true}"#, "\n",
+            r#"{"error":"malformed request"}"#, "\n",
+        ));
+
+        // The server should hang up without waiting for the client to.
+        let mut sock = connect().await;
+        sock.write_all(b"{}\n").await.unwrap();
+        let mut output = String::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            sock.read_to_string(&mut output)
+        ).await.unwrap().unwrap();
+        assert_eq!(output.as_bytes(), MALFORMED);
+    }
+
+    #[tokio::test]
+    async fn split_requests() {
+        let mut sock = connect().await;
+        for chunk in [&b"{\"method\":\"isPr"[..], b"ime\",\"number\":", b"97}\n{\"met"] {
+            sock.write_all(chunk).await.unwrap();
+            sock.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        sock.write_all(b"hod\":\"isPrime\",\"number\":1e2}\n").await.unwrap();
+        sock.shutdown().await.unwrap();
+
+        let mut output = String::new();
+        sock.read_to_string(&mut output).await.unwrap();
+        assert_eq!(output, concat!(
+            r#"{"method":"isPrime","prime":true}"#, "\n",
+            r#"{"method":"isPrime","prime":false}"#, "\n",
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+/*!
+Shutting servers down gracefully.
+
+A [`Coordinator`] hands out [`Shutdown`] handles to servers. When it's
+triggered, servers stop accepting connections and tell their connection
+handlers to wrap up, each in whatever way makes sense for its protocol.
+Handlers register their connections with [`Shutdown::track`] so the
+`Coordinator` can wait for them to finish (up to a point) and report on
+any that didn't.
+*/
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{watch, Notify};
+
+/// How long to wait for connections to finish after a shutdown is
+/// triggered, unless configured otherwise.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Live {
+    next_id: u64,
+    conns: BTreeMap<u64, String>,
+}
+
+#[derive(Default)]
+struct Shared {
+    live: Mutex<Live>,
+    // Notified whenever the last live connection finishes.
+    idle: Notify,
+}
+
+impl Shared {
+    fn live(&self) -> std::sync::MutexGuard<'_, Live> {
+        // Nothing that holds the lock can panic, but just in case.
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/**
+A server's handle on a pending shutdown. It's cheap to clone, and every
+connection handler should get one.
+
+The `Default` one belongs to no `Coordinator`, so it never fires.
+*/
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+    shared: Arc<Shared>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Coordinator::new().handle()
+    }
+}
+
+impl Shutdown {
+    /// Whether shutdown has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until shutdown is triggered (which may be never).
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                // The `Coordinator` is gone without having triggered.
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Register a live connection, described by `what`, until the returned
+    /// `Tracked` is dropped.
+    pub fn track(&self, what: impl Into<String>) -> Tracked {
+        let mut live = self.shared.live();
+        let id = live.next_id;
+        live.next_id += 1;
+        live.conns.insert(id, what.into());
+        Tracked { id, shared: self.shared.clone() }
+    }
+}
+
+/// A connection registered with [`Shutdown::track`]; it counts as live
+/// until this is dropped.
+pub struct Tracked {
+    id: u64,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut live = self.shared.live();
+        live.conns.remove(&self.id);
+        if live.conns.is_empty() {
+            self.shared.idle.notify_waiters();
+        }
+    }
+}
+
+/// Triggers shutdown for every `Shutdown` it's handed out, and waits for
+/// their connections to finish.
+pub struct Coordinator {
+    tx: watch::Sender<bool>,
+    shared: Arc<Shared>,
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Coordinator::new()
+    }
+}
+
+impl Coordinator {
+    pub fn new() -> Coordinator {
+        let (tx, _) = watch::channel(false);
+        Coordinator { tx, shared: Arc::new(Shared::default()) }
+    }
+
+    pub fn handle(&self) -> Shutdown {
+        Shutdown { rx: self.tx.subscribe(), shared: self.shared.clone() }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Descriptions of the connections that are currently live.
+    pub fn live(&self) -> Vec<String> {
+        self.shared.live().conns.values().cloned().collect()
+    }
+
+    /// Wait up to `timeout` for every live connection to finish. Returns
+    /// descriptions of any that haven't.
+    pub async fn drain(&self, timeout: Duration) -> Vec<String> {
+        let idle = async {
+            loop {
+                let notified = self.shared.idle.notified();
+                if self.shared.live().conns.is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, idle).await;
+        self.live()
+    }
+
+    /// Trigger shutdown and drain for up to `timeout`, logging a summary.
+    /// Returns descriptions of the connections that were still live at the
+    /// end.
+    pub async fn shutdown(&self, timeout: Duration) -> Vec<String> {
+        log::info!(
+            "Shutting down; waiting up to {:?} for {} connection(s).",
+            &timeout, self.live().len()
+        );
+        self.trigger();
+
+        let dropped = self.drain(timeout).await;
+        if dropped.is_empty() {
+            log::info!("All connections finished.");
+        } else {
+            log::warn!("Dropping {} connection(s):", dropped.len());
+            for what in dropped.iter() {
+                log::warn!("    {}", what);
+            }
+        }
+        dropped
+    }
+}
+
+/// Print a summary of the `dropped` connections (as returned by
+/// [`Coordinator::shutdown`]) to stderr.
+pub fn report(dropped: &[String]) {
+    if dropped.is_empty() {
+        return;
+    }
+    eprintln!("Shut down with {} connection(s) dropped:", dropped.len());
+    for what in dropped.iter() {
+        eprintln!("    {}", what);
+    }
+}
+
+/// Wait for SIGINT or (on Unix) SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        // This only fails if the signal handler can't be registered.
+        let mut term = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = term.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::{sleep, timeout, Instant};
+
+    #[tokio::test]
+    async fn trigger_and_drain() {
+        assert!(timeout(Duration::from_millis(50), Shutdown::default().wait()).await.is_err());
+
+        let coord = Coordinator::new();
+        let shutdown = coord.handle();
+        assert!(!shutdown.is_triggered());
+        assert!(coord.drain(Duration::from_secs(5)).await.is_empty());
+
+        let quick = shutdown.track("quick");
+        let stuck = shutdown.track("stuck");
+        let waiter = shutdown.clone();
+        let task = tokio::spawn(async move {
+            waiter.wait().await;
+            sleep(Duration::from_millis(20)).await;
+            drop(quick);
+        });
+
+        let start = Instant::now();
+        let dropped = coord.shutdown(Duration::from_millis(200)).await;
+        assert!(shutdown.is_triggered());
+        assert_eq!(dropped, vec!["stuck"]);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        task.await.unwrap();
+
+        drop(stuck);
+        assert!(coord.live().is_empty());
+        let start = Instant::now();
+        assert!(coord.drain(Duration::from_secs(5)).await.is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,1437 @@
+/*!
+Protohackers Problem 0: Smoke Test
+
+Implement the TCP Echo Service; be able to handle at least 5 simultaneous
+clients.
+
+Each client gets its own task, up to `--max-connections` of them at once.
+A client that shuts down its write half gets the rest of its echo, and then
+we shut down ours.
+
+Besides the plain echo loop, there are two faster ways of echoing, chosen
+with `--engine`, so this can double as a target for throughput benchmarks:
+
+  * `copy` reads into one large buffer per connection and writes it straight
+    back, the way `tokio::io::copy` does.
+  * `splice` (Linux only) moves the data from the socket into a pipe and
+    back out again with `splice(2)`, so it never gets copied into userspace.
+
+Whichever engine is used, bytes in and out are counted per connection and
+totalled per server, in the [`Stats`] given to [`serve_with_stats`].
+
+The basic engine can also be made less well-behaved, for testing clients'
+framing and timeouts against: `--mode` echoes whole lines only, reversed or
+not, or upper-cases everything; `--delay` waits before each write; and
+`--split` breaks each response into randomly-sized writes. (A line can be
+no longer than `--buffer-size`; past that, what there is of it gets echoed
+as though it had ended there.)
+
+It doesn't have to be TCP, either. Each `--bind` can be a URI (see
+[`Endpoint`]) for a TCP, UDP, Unix stream or Unix datagram socket, and
+the server listens on all of them at once. Streams get echoed as above
+(and count against the same `--max-connections`); each datagram gets
+echoed back to whoever sent it, with the `--mode` applied to it as though
+it were the whole connection, and `--delay` and `--split` working the
+same way they do for writes.
+*/
+use std::{
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    fmt::{self, Debug, Display, Formatter},
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, RawFd};
+#[cfg(unix)]
+use tokio::net::{UnixDatagram, UnixListener, UnixStream};
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::sleep,
+};
+
+use crate::{
+    config::{Config, Opt},
+    net::{Endpoint, Limit, Listeners, Slot},
+    shutdown::Shutdown,
+};
+
+/// The smoke test server's own options.
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "engine", arg: Some("ENGINE"), many: false,
+        help: "how to echo: basic, copy or splice (Linux only) (default basic)",
+    },
+    Opt {
+        name: "mode", arg: Some("MODE"), many: false,
+        help: "what to echo: raw, line, reverse (each line) or upper (default raw)",
+    },
+    Opt {
+        name: "delay", arg: Some("SECS[-SECS]"), many: false,
+        help: "wait SECS, or a random time in the range, before each write",
+    },
+    Opt {
+        name: "split", arg: None, many: false,
+        help: "break each response into randomly-sized writes",
+    },
+    Opt {
+        name: "seed", arg: Some("N"), many: false,
+        help: "seed for --delay and --split, so runs can be repeated",
+    },
+];
+
+/// The `copy` and `splice` engines move at least this much at a time,
+/// whatever `--buffer-size` says.
+pub const FAST_BUFFER_SIZE: usize = 64 * 1024;
+/// Big enough for any datagram.
+const MAX_DATAGRAM: usize = 64 * 1024;
+/// Most echoes one datagram socket will have waiting out their `--delay` at
+/// once; datagrams that arrive while it's got this many get dropped.
+const MAX_PENDING_ECHOES: usize = 1024;
+
+/// How the echoing actually gets done.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Read into a `--buffer-size` buffer, write it back, and say so.
+    #[default]
+    Basic,
+    /// Read into a large buffer and write it back, quietly.
+    Copy,
+    /// `splice(2)` through a pipe.
+    Splice,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "basic" => Ok(Engine::Basic),
+            "copy" => Ok(Engine::Copy),
+            "splice" if cfg!(target_os = "linux") => Ok(Engine::Splice),
+            "splice" => Err("the splice engine is only available on Linux".into()),
+            _ => Err(format!("unknown engine {:?}", s)),
+        }
+    }
+}
+
+impl Display for Engine {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Engine::Basic => "basic",
+            Engine::Copy => "copy",
+            Engine::Splice => "splice",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What gets echoed back. Everything but `Raw` is only done by
+/// [`Engine::Basic`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Exactly what was read, as soon as it's read.
+    #[default]
+    Raw,
+    /// Each line, once the whole thing (newline and all) has arrived.
+    Line,
+    /// Each line, once it's all arrived, back to front (but still ending in
+    /// a newline).
+    Reverse,
+    /// What was read, as soon as it's read, in upper case.
+    Upper,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Mode::Raw),
+            "line" => Ok(Mode::Line),
+            "reverse" => Ok(Mode::Reverse),
+            "upper" => Ok(Mode::Upper),
+            _ => Err(format!("unknown mode {:?}", s)),
+        }
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Mode::Raw => "raw",
+            Mode::Line => "line",
+            Mode::Reverse => "reverse",
+            Mode::Upper => "upper",
+        };
+        f.write_str(name)
+    }
+}
+
+fn reverse_line(line: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(line) {
+        Ok(s) => s.chars().rev().collect::<String>().into_bytes(),
+        Err(_) => line.iter().rev().copied().collect(),
+    }
+}
+
+impl Mode {
+    /**
+    Return what to echo in response to reading `data`.
+
+    `pending` holds whatever's been read but not echoed yet, which for the
+    line-based modes is the start of an unfinished line.
+    */
+    pub fn respond<'a>(&self, data: &'a [u8], pending: &mut Vec<u8>) -> Cow<'a, [u8]> {
+        match self {
+            Mode::Raw => Cow::Borrowed(data),
+            Mode::Upper => Cow::Owned(data.to_ascii_uppercase()),
+            Mode::Line | Mode::Reverse => {
+                pending.extend_from_slice(data);
+                let end = match pending.iter().rposition(|&b| b == b'\n') {
+                    Some(n) => n + 1,
+                    None => { return Cow::Borrowed(&[]); },
+                };
+                let lines: Vec<u8> = pending.drain(..end).collect();
+                if *self == Mode::Line {
+                    return Cow::Owned(lines);
+                }
+                let mut out = Vec::with_capacity(lines.len());
+                for line in lines.split_inclusive(|&b| b == b'\n') {
+                    out.extend_from_slice(&reverse_line(&line[..line.len() - 1]));
+                    out.push(b'\n');
+                }
+                Cow::Owned(out)
+            },
+        }
+    }
+
+    /// Return what to echo of an unfinished line when the connection ends.
+    pub fn flush(&self, pending: &mut Vec<u8>) -> Vec<u8> {
+        let rest = std::mem::take(pending);
+        match self {
+            Mode::Reverse => reverse_line(&rest),
+            _ => rest,
+        }
+    }
+}
+
+/// How long to wait before each write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delay {
+    Fixed(Duration),
+    /// Some random time between the two.
+    Between(Duration, Duration),
+}
+
+impl FromStr for Delay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn secs(s: &str) -> Result<Duration, String> {
+            let n: f64 = s.trim().parse()
+                .map_err(|_| format!("invalid number of seconds {:?}", s))?;
+            Duration::try_from_secs_f64(n)
+                .map_err(|e| format!("invalid number of seconds {:?}: {}", s, &e))
+        }
+
+        match s.split_once('-') {
+            None => Ok(Delay::Fixed(secs(s)?)),
+            Some((lo, hi)) => {
+                let (lo, hi) = (secs(lo)?, secs(hi)?);
+                if lo > hi {
+                    Err(format!("delay range {:?} runs backwards", s))
+                } else {
+                    Ok(Delay::Between(lo, hi))
+                }
+            },
+        }
+    }
+}
+
+impl Delay {
+    fn pick(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            Delay::Fixed(d) => d,
+            Delay::Between(lo, hi) => lo + (hi - lo).mul_f64(rng.unit()),
+        }
+    }
+}
+
+/// SplitMix64. Nowhere near good enough for anything that matters, but
+/// plenty for deciding how long to keep a client waiting.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: Option<u64>) -> Rng {
+        Rng(seed.unwrap_or_else(|| RandomState::new().build_hasher().finish()))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Somewhere in `1..=n`.
+    fn upto(&mut self, n: usize) -> usize {
+        1 + (self.next() % n as u64) as usize
+    }
+
+    /// Somewhere in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Everything about how to echo that isn't common to all the servers.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub engine: Engine,
+    pub mode: Mode,
+    pub delay: Option<Delay>,
+    pub split: bool,
+    pub seed: Option<u64>,
+}
+
+impl Options {
+    /// Get the smoke test server's [`OPTS`] out of `cfg`.
+    pub fn from_config(cfg: &Config) -> Result<Options, String> {
+        let opts = Options {
+            engine: cfg.get("engine")?.unwrap_or_default(),
+            mode: cfg.get("mode")?.unwrap_or_default(),
+            delay: cfg.get("delay")?,
+            split: cfg.get("split")?.unwrap_or(false),
+            seed: cfg.get("seed")?,
+        };
+        if opts.engine != Engine::Basic && !opts.is_plain() {
+            return Err(format!(
+                "--mode, --delay and --split need the basic engine, not {}",
+                &opts.engine
+            ));
+        }
+        Ok(opts)
+    }
+
+    /// Whether this just echoes straight back.
+    fn is_plain(&self) -> bool {
+        self.mode == Mode::Raw && self.delay.is_none() && !self.split
+    }
+}
+
+/// Bytes moved over one connection, or over several.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Datagrams that arrived when too many echoes were already waiting
+    /// to be sent, and didn't get one.
+    pub dropped: u64,
+}
+
+/// Running totals for every connection a server has finished with.
+#[derive(Debug, Default)]
+pub struct Stats {
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Stats {
+    /// Count a finished connection, and what went over it.
+    pub fn add(&self, counts: Counters) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.add_bytes(counts);
+    }
+
+    /// Count bytes that didn't go over a connection (because they were
+    /// datagrams).
+    pub fn add_bytes(&self, counts: Counters) {
+        self.bytes_in.fetch_add(counts.bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(counts.bytes_out, Ordering::Relaxed);
+        self.dropped.fetch_add(counts.dropped, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn totals(&self) -> Counters {
+        Counters {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Break `data` up into the pieces to send it as, each with how long to wait
+/// first, as `opts` says.
+fn schedule<'a>(
+    mut data: &'a [u8],
+    opts: &Options,
+    rng: &mut Rng,
+) -> Vec<(Option<Duration>, &'a [u8])> {
+    let mut pieces = Vec::new();
+    while !data.is_empty() {
+        let n = if opts.split { rng.upto(data.len()) } else { data.len() };
+        let delay = opts.delay.map(|d| d.pick(rng));
+        pieces.push((delay, &data[..n]));
+        data = &data[n..];
+    }
+    pieces
+}
+
+/// Write `data` to `sock`, with whatever delays and splitting `opts` say.
+async fn respond<S: Stream>(
+    sock: &mut S,
+    data: &[u8],
+    opts: &Options,
+    rng: &mut Rng,
+    counts: &mut Counters,
+) -> io::Result<()> {
+    for (delay, piece) in schedule(data, opts, rng) {
+        if let Some(delay) = delay {
+            sleep(delay).await;
+        }
+        sock.write_all(piece).await?;
+        counts.bytes_out += piece.len() as u64;
+    }
+    Ok(())
+}
+
+/// The plain echo loop, through a `buffsize`-byte buffer, doing whatever
+/// else `opts` asks for.
+async fn basic<S: Stream>(
+    sock: &mut S,
+    opts: &Options,
+    buffsize: usize,
+    shutdown: &Shutdown,
+    counts: &mut Counters,
+) -> io::Result<()> {
+    let mut buff = vec![0u8; buffsize];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut rng = Rng::new(opts.seed);
+
+    loop {
+        let res = tokio::select! {
+            res = sock.read(&mut buff) => res,
+            _ = shutdown.wait() => { break; },
+        };
+        match res {
+            Ok(0) => { break; }
+            Ok(n) => {
+                counts.bytes_in += n as u64;
+                let mut out = opts.mode.respond(&buff[..n], &mut pending);
+                // Don't wait forever for the end of a line.
+                if pending.len() >= buffsize {
+                    let mut long = out.into_owned();
+                    long.extend_from_slice(&opts.mode.flush(&mut pending));
+                    out = Cow::Owned(long);
+                }
+                respond(sock, &out, opts, &mut rng, counts).await?;
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => { continue; },
+            Err(e) => { return Err(e); },
+        }
+    }
+
+    let rest = opts.mode.flush(&mut pending);
+    respond(sock, &rest, opts, &mut rng, counts).await
+}
+
+/// Like [`basic`], but with a buffer of at least [`FAST_BUFFER_SIZE`] and
+/// none of the extras.
+async fn copy<S: Stream>(
+    sock: &mut S,
+    buffsize: usize,
+    shutdown: &Shutdown,
+    counts: &mut Counters,
+) -> io::Result<()> {
+    let mut buff = vec![0u8; buffsize.max(FAST_BUFFER_SIZE)];
+
+    loop {
+        let n = tokio::select! {
+            res = sock.read(&mut buff) => res?,
+            _ = shutdown.wait() => { return Ok(()); },
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        counts.bytes_in += n as u64;
+        sock.write_all(&buff[..n]).await?;
+        counts.bytes_out += n as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    /*!
+    Echoing with `splice(2)`: each chunk goes from the socket into a pipe,
+    and then from the pipe back into the socket, without ever being copied
+    into our memory.
+    */
+    use std::{
+        io::{self, ErrorKind},
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        ptr,
+    };
+    use tokio::io::Interest;
+
+    use super::{Counters, Stream, FAST_BUFFER_SIZE};
+    use crate::shutdown::Shutdown;
+
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Pipe> {
+            let mut fds: [RawFd; 2] = [-1, -1];
+            // SAFETY: `fds` has room for the two descriptors `pipe2()` fills in.
+            let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: both descriptors are open, and nothing else owns them.
+            unsafe {
+                Ok(Pipe {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                })
+            }
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: null offsets just mean "wherever the descriptor is at", and
+        // neither descriptor can be closed out from under us.
+        let n = unsafe {
+            libc::splice(
+                from, ptr::null_mut(), to, ptr::null_mut(), len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// Move whatever `sock` has to read (up to a pipe's worth) into the
+    /// empty `pipe`, returning how much that was; 0 means end of stream.
+    ///
+    /// Because the pipe is empty, a `WouldBlock` can only come from the
+    /// socket, which is what `try_io()` needs to know to wait properly.
+    async fn fill<S: Stream>(sock: &S, pipe: &Pipe) -> io::Result<usize> {
+        loop {
+            sock.readable().await?;
+            let res = sock.try_io(Interest::READABLE, || {
+                splice(sock.raw_fd(), pipe.write.as_raw_fd(), FAST_BUFFER_SIZE)
+            });
+            match res {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => { continue; },
+                res => { return res; },
+            }
+        }
+    }
+
+    /// Move all `len` bytes in `pipe` out into `sock`.
+    async fn drain<S: Stream>(sock: &S, pipe: &Pipe, mut len: usize) -> io::Result<()> {
+        while len > 0 {
+            sock.writable().await?;
+            let res = sock.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), sock.raw_fd(), len)
+            });
+            match res {
+                Ok(n) => { len -= n; },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => { continue; },
+                Err(e) => { return Err(e); },
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn echo<S: Stream>(
+        sock: &S,
+        shutdown: &Shutdown,
+        counts: &mut Counters,
+    ) -> io::Result<()> {
+        let pipe = Pipe::new()?;
+        loop {
+            let n = tokio::select! {
+                res = fill(sock, &pipe) => res?,
+                _ = shutdown.wait() => { return Ok(()); },
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            counts.bytes_in += n as u64;
+            drain(sock, &pipe, n).await?;
+            counts.bytes_out += n as u64;
+        }
+    }
+}
+
+/// A connection [`handle`] can echo over: TCP, or a Unix stream socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {
+    // The splice engine works on the socket itself, rather than through
+    // `AsyncRead` and `AsyncWrite`.
+    #[cfg(target_os = "linux")]
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send;
+    #[cfg(target_os = "linux")]
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send;
+    #[cfg(target_os = "linux")]
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R>;
+    #[cfg(target_os = "linux")]
+    fn raw_fd(&self) -> RawFd;
+}
+
+macro_rules! impl_stream {
+    ($($t:ty),*) => {$(
+        impl Stream for $t {
+            #[cfg(target_os = "linux")]
+            fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+                <$t>::readable(self)
+            }
+            #[cfg(target_os = "linux")]
+            fn writable(&self) -> impl Future<Output = io::Result<()>> + Send {
+                <$t>::writable(self)
+            }
+            #[cfg(target_os = "linux")]
+            fn try_io<R>(
+                &self,
+                interest: Interest,
+                f: impl FnOnce() -> io::Result<R>,
+            ) -> io::Result<R> {
+                <$t>::try_io(self, interest, f)
+            }
+            #[cfg(target_os = "linux")]
+            fn raw_fd(&self) -> RawFd {
+                self.as_raw_fd()
+            }
+        }
+    )*};
+}
+
+impl_stream!(TcpStream);
+#[cfg(unix)]
+impl_stream!(UnixStream);
+
+/// We're not going to try for any error recovery at all. We just drop
+/// clients on the floor if there's a problem.
+///
+/// On shutdown, whatever has already been read gets echoed, and then the
+/// connection is closed. Returns how much got echoed.
+pub async fn handle<S: Stream>(
+    mut sock: S,
+    opts: &Options,
+    buffsize: usize,
+    shutdown: &Shutdown,
+) -> Counters {
+    let mut counts = Counters::default();
+
+    let res = match opts.engine {
+        Engine::Basic => basic(&mut sock, opts, buffsize, shutdown, &mut counts).await,
+        Engine::Copy => copy(&mut sock, buffsize, shutdown, &mut counts).await,
+        #[cfg(target_os = "linux")]
+        Engine::Splice => splice::echo(&sock, shutdown, &mut counts).await,
+        #[cfg(not(target_os = "linux"))]
+        Engine::Splice => Err(io::Error::new(ErrorKind::Unsupported, "splice(2) is Linux-only")),
+    };
+    if let Err(e) = res {
+        log::warn!("Error echoing: {}", &e);
+    }
+
+    if let Err(e) = sock.shutdown().await {
+        log::warn!("Error shutting down socket: {}", &e);
+    }
+    counts
+}
+
+/// A datagram socket to echo over.
+trait Datagrams {
+    type Addr: Debug + Send + Sync;
+
+    async fn recv(&self, buff: &mut [u8]) -> io::Result<(usize, Self::Addr)>;
+    async fn send(&self, data: &[u8], to: &Self::Addr) -> io::Result<usize>;
+}
+
+impl Datagrams for UdpSocket {
+    type Addr = SocketAddr;
+
+    async fn recv(&self, buff: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buff).await
+    }
+
+    async fn send(&self, data: &[u8], to: &SocketAddr) -> io::Result<usize> {
+        self.send_to(data, to).await
+    }
+}
+
+#[cfg(unix)]
+impl Datagrams for UnixDatagram {
+    type Addr = tokio::net::unix::SocketAddr;
+
+    async fn recv(&self, buff: &mut [u8]) -> io::Result<(usize, Self::Addr)> {
+        self.recv_from(buff).await
+    }
+
+    async fn send(&self, data: &[u8], to: &Self::Addr) -> io::Result<usize> {
+        match to.as_pathname() {
+            Some(path) => self.send_to(data, path).await,
+            None => Err(io::Error::new(
+                ErrorKind::AddrNotAvailable,
+                "can't reply to a socket that isn't bound to a path",
+            )),
+        }
+    }
+}
+
+/// Everything the smoke test server listens on; see [`Endpoint`].
+#[derive(Default)]
+pub struct Sockets {
+    tcp: Option<Listeners>,
+    udp: Vec<UdpSocket>,
+    #[cfg(unix)]
+    unix: Vec<(PathBuf, UnixListener)>,
+    #[cfg(unix)]
+    unix_datagram: Vec<(PathBuf, UnixDatagram)>,
+}
+
+impl From<Listeners> for Sockets {
+    fn from(listeners: Listeners) -> Self {
+        Sockets { tcp: Some(listeners), ..Default::default() }
+    }
+}
+
+impl From<TcpListener> for Sockets {
+    fn from(listener: TcpListener) -> Self {
+        Listeners::from(listener).into()
+    }
+}
+
+/// Whether the socket at `path` is a leftover that nothing is bound to any
+/// more. Connecting to one of those is refused, whatever kind of socket it
+/// was; connecting to a live one either works or (if it's the other kind)
+/// fails some other way.
+#[cfg(unix)]
+fn stale(path: &PathBuf) -> bool {
+    use std::os::unix::net;
+
+    let refused = |res: io::Result<()>| {
+        matches!(res, Err(e) if e.kind() == ErrorKind::ConnectionRefused)
+    };
+    refused(net::UnixStream::connect(path).map(drop))
+        && refused(net::UnixDatagram::unbound().and_then(|sock| sock.connect(path)))
+}
+
+/// Bind a Unix socket at `path`, first removing any socket a previous run
+/// left lying there. A socket that something's still bound to stays put,
+/// and binding fails.
+#[cfg(unix)]
+fn bind_unix<T>(
+    path: &PathBuf,
+    bind: impl FnOnce(&PathBuf) -> io::Result<T>,
+) -> Result<T, String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            if !stale(path) {
+                return Err(format!("unable to bind {:?}: something is already using it", path));
+            }
+            std::fs::remove_file(path)
+                .map_err(|e| format!("unable to remove old socket {:?}: {}", path, &e))?;
+        }
+    }
+    bind(path).map_err(|e| format!("unable to bind {:?}: {}", path, &e))
+}
+
+impl Sockets {
+    /// Bind each of `addrs`, which are [`Endpoint`]s; there must be at least
+    /// one.
+    pub async fn bind(addrs: &[String]) -> Result<Sockets, String> {
+        if addrs.is_empty() {
+            return Err("no addresses to bind".into());
+        }
+        let mut sockets = Sockets::default();
+        let mut tcp = Vec::new();
+        for addr in addrs.iter() {
+            match addr.parse::<Endpoint>()? {
+                Endpoint::Tcp(addr) => tcp.push(addr),
+                Endpoint::Udp(addr) => {
+                    let sock = UdpSocket::bind(&addr).await
+                        .map_err(|e| format!("unable to bind {}: {}", &addr, &e))?;
+                    sockets.udp.push(sock);
+                },
+                #[cfg(unix)]
+                Endpoint::Unix(path) => {
+                    let listener = bind_unix(&path, |p| UnixListener::bind(p))?;
+                    sockets.unix.push((path, listener));
+                },
+                #[cfg(unix)]
+                Endpoint::UnixDatagram(path) => {
+                    let sock = bind_unix(&path, |p| UnixDatagram::bind(p))?;
+                    sockets.unix_datagram.push((path, sock));
+                },
+                #[cfg(not(unix))]
+                Endpoint::Unix(_) | Endpoint::UnixDatagram(_) => {
+                    return Err(format!("can't bind {}: no Unix sockets here", addr));
+                },
+            }
+        }
+        if !tcp.is_empty() {
+            sockets.tcp = Some(Listeners::bind(&tcp).await?);
+        }
+        Ok(sockets)
+    }
+
+    /// Everything that's bound, written the way [`Endpoint`]s are.
+    pub fn endpoints(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        if let Some(tcp) = &self.tcp {
+            names.extend(tcp.local_addrs().iter().map(|a| format!("tcp://{}", a)));
+        }
+        for sock in self.udp.iter() {
+            if let Ok(addr) = sock.local_addr() {
+                names.push(format!("udp://{}", addr));
+            }
+        }
+        #[cfg(unix)]
+        {
+            for (path, _) in self.unix.iter() {
+                names.push(format!("unix://{}", path.display()));
+            }
+            for (path, _) in self.unix_datagram.iter() {
+                names.push(format!("unixgram://{}", path.display()));
+            }
+        }
+        names
+    }
+}
+
+/// What every task serving part of one server needs.
+#[derive(Clone)]
+struct Ctx {
+    opts: Arc<Options>,
+    buffsize: usize,
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+}
+
+impl Ctx {
+    /// Echo `sock` in its own task, holding `slot` until it's done.
+    fn spawn<S: Stream + 'static>(&self, sock: S, client: String, slot: Slot) {
+        log::info!("Accepted incoming from {}", &client);
+        let tracked = self.shutdown.track(format!("smoke client {}", &client));
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            let counts = handle(sock, &ctx.opts, ctx.buffsize, &ctx.shutdown).await;
+            log::info!(
+                "Dropping connection from {}: {} bytes in, {} bytes out.",
+                &client, counts.bytes_in, counts.bytes_out
+            );
+            ctx.stats.add(counts);
+            drop((slot, tracked));
+        });
+    }
+}
+
+async fn accept_tcp(listener: Listeners, ctx: Ctx) {
+    loop {
+        let res = tokio::select! {
+            res = listener.accept() => res,
+            _ = ctx.shutdown.wait() => { break; },
+        };
+        match res {
+            Ok((socket, addr, slot)) => ctx.spawn(socket, format!("{:?}", &addr), slot),
+            Err(e) => {
+                log::error!("Error with incoming connection: {}", &e);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn accept_unix(path: PathBuf, listener: UnixListener, limit: Limit, ctx: Ctx) {
+    let mut n: usize = 0;
+    loop {
+        let res = tokio::select! {
+            res = async { (limit.acquire().await, listener.accept().await) } => res,
+            _ = ctx.shutdown.wait() => { break; },
+        };
+        match res {
+            (slot, Ok((socket, _))) => {
+                n += 1;
+                ctx.spawn(socket, format!("{} #{}", path.display(), n), slot);
+            },
+            (_, Err(e)) => {
+                log::error!("Error with incoming connection: {}", &e);
+            }
+        }
+    }
+    if let Err(e) = std::fs::remove_file(&path) {
+        log::error!("Error removing socket {:?}: {}", &path, &e);
+    }
+}
+
+/// Send the `pieces` of an echo to `to`, waiting before each one as
+/// scheduled, and return how many bytes got sent.
+async fn send_pieces<D: Datagrams>(
+    sock: &D,
+    pieces: Vec<(Option<Duration>, Vec<u8>)>,
+    to: D::Addr,
+) -> u64 {
+    let mut sent = 0;
+    for (delay, piece) in pieces {
+        if let Some(delay) = delay {
+            sleep(delay).await;
+        }
+        match sock.send(&piece, &to).await {
+            Ok(n) => { sent += n as u64; },
+            Err(e) => {
+                log::warn!("Error echoing datagram to {:?}: {}", &to, &e);
+                break;
+            },
+        }
+    }
+    sent
+}
+
+/**
+Echo each datagram that arrives on `sock` back to its sender.
+
+Each echo is sent alongside receiving more datagrams, so one sender's
+`--delay` doesn't hold up anybody else's. Echoes that are still waiting at
+`shutdown` don't get sent. There can only be [`MAX_PENDING_ECHOES`] of them
+waiting at once, so a flood can't make them pile up without limit;
+datagrams past that are dropped, and counted.
+*/
+async fn echo_datagrams<D: Datagrams>(sock: D, ctx: Ctx) -> Counters {
+    let mut buff = vec![0u8; MAX_DATAGRAM];
+    let mut rng = Rng::new(ctx.opts.seed);
+    let mut counts = Counters::default();
+    let mut sends = FuturesUnordered::new();
+
+    loop {
+        let res = tokio::select! {
+            res = sock.recv(&mut buff) => res,
+            Some(n) = sends.next(), if !sends.is_empty() => {
+                counts.bytes_out += n;
+                continue;
+            },
+            _ = ctx.shutdown.wait() => { break; },
+        };
+        let (n, from) = match res {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Error receiving datagram: {}", &e);
+                continue;
+            },
+        };
+        counts.bytes_in += n as u64;
+        if sends.len() >= MAX_PENDING_ECHOES {
+            counts.dropped += 1;
+            continue;
+        }
+
+        let mut pending = Vec::new();
+        let mut out = ctx.opts.mode.respond(&buff[..n], &mut pending).into_owned();
+        out.extend_from_slice(&ctx.opts.mode.flush(&mut pending));
+
+        let mut pieces: Vec<(Option<Duration>, Vec<u8>)> = schedule(&out, &ctx.opts, &mut rng)
+            .into_iter()
+            .map(|(delay, piece)| (delay, piece.to_vec()))
+            .collect();
+        if pieces.is_empty() {
+            // An empty datagram still deserves an (empty) echo.
+            pieces.push((ctx.opts.delay.map(|d| d.pick(&mut rng)), Vec::new()));
+        }
+        sends.push(send_pieces(&sock, pieces, from));
+    }
+    counts
+}
+
+/// Serve everything in `sockets` until `shutdown`, echoing each stream
+/// connection in its own task.
+pub async fn serve(
+    sockets: impl Into<Sockets>,
+    opts: Options,
+    cfg: Config,
+    shutdown: Shutdown,
+) {
+    serve_with_stats(sockets, opts, cfg, shutdown, Arc::default()).await
+}
+
+/// Like [`serve`], but count what gets echoed in `stats`. Connections that
+/// are still draining when this returns get counted when they finish, so
+/// the totals are only complete once they have.
+pub async fn serve_with_stats(
+    sockets: impl Into<Sockets>,
+    opts: Options,
+    cfg: Config,
+    shutdown: Shutdown,
+    stats: Arc<Stats>,
+) {
+    let sockets = sockets.into();
+    let limit = Limit::new(cfg.max_connections);
+    let ctx = Ctx {
+        opts: Arc::new(opts),
+        buffsize: cfg.buffer_size,
+        stats,
+        shutdown,
+    };
+
+    let mut streams = Vec::new();
+    let mut datagrams = Vec::new();
+    if let Some(listener) = sockets.tcp {
+        let listener = listener.limit_by(limit.clone());
+        streams.push(tokio::spawn(accept_tcp(listener, ctx.clone())));
+    }
+    for sock in sockets.udp {
+        datagrams.push(tokio::spawn(echo_datagrams(sock, ctx.clone())));
+    }
+    #[cfg(unix)]
+    {
+        for (path, listener) in sockets.unix {
+            streams.push(tokio::spawn(accept_unix(path, listener, limit.clone(), ctx.clone())));
+        }
+        for (path, sock) in sockets.unix_datagram {
+            let ctx = ctx.clone();
+            datagrams.push(tokio::spawn(async move {
+                let counts = echo_datagrams(sock, ctx).await;
+                if let Err(e) = std::fs::remove_file(&path) {
+                    log::error!("Error removing socket {:?}: {}", &path, &e);
+                }
+                counts
+            }));
+        }
+    }
+
+    for task in streams {
+        let _ = task.await;
+    }
+    for task in datagrams {
+        if let Ok(counts) = task.await {
+            ctx.stats.add_bytes(counts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{net::SocketAddr, time::Duration};
+    use tokio::{net::TcpListener, time::timeout};
+
+    async fn start(cfg: Config) -> SocketAddr {
+        start_with(Options::default(), cfg).await
+    }
+
+    async fn start_with(opts: Options, cfg: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, opts, cfg, Shutdown::default()));
+        addr
+    }
+
+    #[tokio::test]
+    async fn many_at_once() {
+        let addr = start(Config::default()).await;
+
+        // Everybody connects before anybody finishes.
+        let mut socks = Vec::new();
+        for _ in 0..20 {
+            socks.push(TcpStream::connect(addr).await.unwrap());
+        }
+        for (n, sock) in socks.iter_mut().enumerate() {
+            sock.write_all(format!("client {}\n", n).as_bytes()).await.unwrap();
+        }
+        for (n, sock) in socks.iter_mut().enumerate().rev() {
+            let expected = format!("client {}\n", n);
+            let mut buff = vec![0u8; expected.len()];
+            timeout(Duration::from_secs(5), sock.read_exact(&mut buff)).await
+                .unwrap().unwrap();
+            assert_eq!(buff, expected.as_bytes());
+        }
+    }
+
+    async fn half_close(engine: Engine) {
+        let opts = Options { engine, ..Default::default() };
+        let addr = start_with(opts, Config::default()).await;
+        let data: Vec<u8> = (0..1_000_000u32).map(|n| (n % 251) as u8).collect();
+
+        let (mut r, mut w) = TcpStream::connect(addr).await.unwrap().into_split();
+        // More than fits in the socket buffers, so this has to be read while
+        // it's still being written.
+        let to_send = data.clone();
+        let writer = tokio::spawn(async move {
+            w.write_all(&to_send).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+
+        let mut echoed = Vec::new();
+        timeout(Duration::from_secs(10), r.read_to_end(&mut echoed)).await
+            .unwrap().unwrap();
+        writer.await.unwrap();
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn half_close_basic() {
+        half_close(Engine::Basic).await;
+    }
+
+    #[tokio::test]
+    async fn half_close_copy() {
+        half_close(Engine::Copy).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn half_close_splice() {
+        half_close(Engine::Splice).await;
+    }
+
+    #[test]
+    fn engine_option() {
+        let mut cfg = Config::default();
+        assert_eq!(Options::from_config(&cfg).unwrap().engine, Engine::Basic);
+        cfg.set("engine", Engine::Copy);
+        assert_eq!(Options::from_config(&cfg).unwrap().engine, Engine::Copy);
+        cfg.set("engine", "zero-copy");
+        assert!(Options::from_config(&cfg).is_err());
+    }
+
+    #[test]
+    fn modes() {
+        let mut pending = Vec::new();
+        assert_eq!(&*Mode::Raw.respond(b"ab\ncd", &mut pending), b"ab\ncd");
+        assert_eq!(&*Mode::Upper.respond(b"ab\ncd", &mut pending), b"AB\nCD");
+        assert!(pending.is_empty());
+
+        assert_eq!(&*Mode::Line.respond(b"ab\nc", &mut pending), b"ab\n");
+        assert_eq!(&*Mode::Line.respond(b"d", &mut pending), b"");
+        assert_eq!(&*Mode::Line.respond(b"\nef\ngh", &mut pending), b"cd\nef\n");
+        assert_eq!(Mode::Line.flush(&mut pending), b"gh");
+
+        assert_eq!(&*Mode::Reverse.respond(b"abc\nd", &mut pending), b"cba\n");
+        assert_eq!(&*Mode::Reverse.respond("é!\n\nxy".as_bytes(), &mut pending), "!éd\n\n".as_bytes());
+        assert_eq!(Mode::Reverse.flush(&mut pending), b"yx");
+        assert!(pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_lines() {
+        let opts = Options { mode: Mode::Reverse, ..Default::default() };
+        let mut cfg = Config::default();
+        cfg.buffer_size = 16;
+        let addr = start_with(opts, cfg).await;
+
+        // No newline, and the socket stays open, but it still gets echoed
+        // a buffer's worth at a time.
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(&[b'a'; 1000]).await.unwrap();
+        let mut buff = vec![0u8; 1000 - 16];
+        timeout(Duration::from_secs(5), sock.read_exact(&mut buff)).await
+            .unwrap().unwrap();
+        assert!(buff.iter().all(|&b| b == b'a'));
+    }
+
+    #[test]
+    fn other_options() {
+        let mut cfg = Config::default();
+        cfg.set("mode", Mode::Reverse);
+        cfg.set("delay", "0.25-1.5");
+        cfg.set("split", true);
+        cfg.set("seed", 7);
+        let opts = Options::from_config(&cfg).unwrap();
+        assert_eq!(opts.mode, Mode::Reverse);
+        assert_eq!(
+            opts.delay,
+            Some(Delay::Between(Duration::from_millis(250), Duration::from_millis(1500)))
+        );
+        assert!(opts.split);
+        assert_eq!(opts.seed, Some(7));
+
+        // Only the basic engine can do any of that.
+        cfg.set("engine", Engine::Copy);
+        assert!(Options::from_config(&cfg).is_err());
+
+        assert_eq!("2".parse(), Ok(Delay::Fixed(Duration::from_secs(2))));
+        assert!("2-1".parse::<Delay>().is_err());
+        assert!("soon".parse::<Delay>().is_err());
+    }
+
+    #[tokio::test]
+    async fn counts_what_it_echoes() {
+        use crate::shutdown::Coordinator;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(Stats::default());
+        let coordinator = Coordinator::new();
+        let server = tokio::spawn(serve_with_stats(
+            listener, Options::default(), Config::default(), coordinator.handle(), stats.clone()
+        ));
+
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(b"hello").await.unwrap();
+        let mut buff = [0u8; 5];
+        timeout(Duration::from_secs(5), sock.read_exact(&mut buff)).await.unwrap().unwrap();
+
+        // Still connected at shutdown, and counted once it's been drained.
+        assert!(coordinator.shutdown(Duration::from_secs(1)).await.is_empty());
+        server.await.unwrap();
+        assert_eq!(stats.connections(), 1);
+        assert_eq!(stats.totals(), Counters { bytes_in: 5, bytes_out: 5, dropped: 0 });
+    }
+
+    #[tokio::test]
+    async fn datagram_flood() {
+        use crate::shutdown::Coordinator;
+
+        let sockets = Sockets::bind(&["udp://127.0.0.1:0".to_string()]).await.unwrap();
+        let addr = sockets.endpoints()[0].strip_prefix("udp://").unwrap().to_string();
+        let opts = Options { delay: Some(Delay::Fixed(Duration::from_secs(60))), ..Default::default() };
+        let stats = Arc::new(Stats::default());
+        let coordinator = Coordinator::new();
+        let server = tokio::spawn(serve_with_stats(
+            sockets, opts, Config::default(), coordinator.handle(), stats.clone()
+        ));
+
+        // Slowly enough that none of them get lost on the way.
+        let n = MAX_PENDING_ECHOES + 100;
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(&addr).await.unwrap();
+        for i in 0..n {
+            udp.send(b"x").await.unwrap();
+            if i % 50 == 0 {
+                sleep(Duration::from_millis(5)).await;
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+
+        assert!(coordinator.shutdown(Duration::from_secs(1)).await.is_empty());
+        server.await.unwrap();
+        let totals = stats.totals();
+        assert_eq!(totals.bytes_in, n as u64);
+        assert_eq!(totals.dropped, 100);
+        assert_eq!(totals.bytes_out, 0);
+    }
+
+    #[tokio::test]
+    async fn delayed_datagrams() {
+        use crate::shutdown::Coordinator;
+
+        let sockets = Sockets::bind(&["udp://127.0.0.1:0".to_string()]).await.unwrap();
+        let addr = sockets.endpoints()[0].strip_prefix("udp://").unwrap().to_string();
+        let opts = Options { delay: Some(Delay::Fixed(Duration::from_millis(500))), ..Default::default() };
+        let coordinator = Coordinator::new();
+        let server = tokio::spawn(serve(sockets, opts, Config::default(), coordinator.handle()));
+
+        // Each sender waits out its own delay, not everybody else's too.
+        let start = tokio::time::Instant::now();
+        let mut peers = Vec::new();
+        for n in 0..4u8 {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            udp.connect(&addr).await.unwrap();
+            udp.send(&[n]).await.unwrap();
+            peers.push(udp);
+        }
+        let mut buff = [0u8; 8];
+        for (n, udp) in peers.iter().enumerate() {
+            let len = timeout(Duration::from_secs(5), udp.recv(&mut buff)).await.unwrap().unwrap();
+            assert_eq!(&buff[..len], &[n as u8]);
+        }
+        assert!(start.elapsed() < Duration::from_millis(1500), "{:?}", start.elapsed());
+
+        // An echo that's still waiting doesn't hold up shutting down.
+        peers[0].send(b"late").await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        let start = tokio::time::Instant::now();
+        assert!(coordinator.shutdown(Duration::from_secs(1)).await.is_empty());
+        timeout(Duration::from_millis(200), server).await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn delayed_and_split() {
+        let opts = Options {
+            mode: Mode::Reverse,
+            delay: Some(Delay::Fixed(Duration::from_millis(20))),
+            split: true,
+            seed: Some(1),
+            ..Default::default()
+        };
+        let addr = start_with(opts, Config::default()).await;
+        let line = b"The quick brown fox jumps over the lazy dog.\n";
+
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        let start = tokio::time::Instant::now();
+        sock.write_all(line).await.unwrap();
+        sock.shutdown().await.unwrap();
+
+        // Read it back a write at a time, to see that it came in pieces.
+        let mut echoed = Vec::new();
+        let mut reads = 0;
+        let mut buff = [0u8; 64];
+        loop {
+            match timeout(Duration::from_secs(5), sock.read(&mut buff)).await.unwrap().unwrap() {
+                0 => { break; },
+                n => { echoed.extend_from_slice(&buff[..n]); reads += 1; },
+            }
+        }
+        assert_eq!(echoed, b".god yzal eht revo spmuj xof nworb kciuq ehT\n");
+        assert!(reads > 1);
+        assert!(start.elapsed() >= Duration::from_millis(20 * reads));
+    }
+
+    #[tokio::test]
+    async fn limit() {
+        let mut cfg = Config::default();
+        cfg.max_connections = Some(2);
+        let addr = start(cfg).await;
+
+        let mut a = TcpStream::connect(addr).await.unwrap();
+        let mut b = TcpStream::connect(addr).await.unwrap();
+        let mut c = TcpStream::connect(addr).await.unwrap();
+        let mut buff = [0u8; 1];
+        for sock in [&mut a, &mut b, &mut c] {
+            sock.write_all(b"x").await.unwrap();
+        }
+        for sock in [&mut a, &mut b] {
+            sock.read_exact(&mut buff).await.unwrap();
+        }
+        // The third connection waits its turn...
+        assert!(timeout(Duration::from_millis(200), c.read_exact(&mut buff)).await.is_err());
+
+        // ...which comes when one of the others leaves.
+        a.shutdown().await.unwrap();
+        assert_eq!(a.read(&mut buff).await.unwrap(), 0);
+        timeout(Duration::from_secs(5), c.read_exact(&mut buff)).await.unwrap().unwrap();
+        assert_eq!(&buff, b"x");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn other_transports() {
+        use crate::shutdown::Coordinator;
+
+        let dir = std::env::temp_dir().join(format!("ph-smoke-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stream_path = dir.join("stream.sock");
+        let dgram_path = dir.join("dgram.sock");
+        let addrs = vec![
+            "udp://127.0.0.1:0".to_string(),
+            format!("unix://{}", stream_path.display()),
+            format!("unixgram://{}", dgram_path.display()),
+        ];
+        let sockets = Sockets::bind(&addrs).await.unwrap();
+        let endpoints = sockets.endpoints();
+        assert_eq!(endpoints.len(), 3);
+        let udp_addr = endpoints[0].strip_prefix("udp://").unwrap().to_string();
+
+        let opts = Options { mode: Mode::Upper, ..Default::default() };
+        let coordinator = Coordinator::new();
+        let server = tokio::spawn(serve(sockets, opts, Config::default(), coordinator.handle()));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(&udp_addr).await.unwrap();
+        udp.send(b"over udp").await.unwrap();
+        let mut buff = [0u8; 64];
+        let n = timeout(Duration::from_secs(5), udp.recv(&mut buff)).await.unwrap().unwrap();
+        assert_eq!(&buff[..n], b"OVER UDP");
+
+        let mut stream = UnixStream::connect(&stream_path).await.unwrap();
+        stream.write_all(b"over a unix stream").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut echoed)).await
+            .unwrap().unwrap();
+        assert_eq!(echoed, b"OVER A UNIX STREAM");
+
+        let client_path = dir.join("client.sock");
+        let _ = std::fs::remove_file(&client_path);
+        let dgram = UnixDatagram::bind(&client_path).unwrap();
+        dgram.send_to(b"over a unix datagram", &dgram_path).await.unwrap();
+        let n = timeout(Duration::from_secs(5), dgram.recv(&mut buff)).await.unwrap().unwrap();
+        assert_eq!(&buff[..n], b"OVER A UNIX DATAGRAM");
+
+        assert!(coordinator.shutdown(Duration::from_secs(1)).await.is_empty());
+        server.await.unwrap();
+        // The server cleans up after itself.
+        assert!(!stream_path.exists());
+        assert!(!dgram_path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_in_use() {
+        let dir = std::env::temp_dir().join(format!("ph-smoke-in-use-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stream_path = dir.join("stream.sock");
+        let dgram_path = dir.join("dgram.sock");
+        let stream = format!("unix://{}", stream_path.display());
+        let dgram = format!("unixgram://{}", dgram_path.display());
+
+        // Somebody else is using them...
+        let listener = UnixListener::bind(&stream_path).unwrap();
+        let sock = UnixDatagram::bind(&dgram_path).unwrap();
+        for addr in [&stream, &dgram] {
+            assert!(Sockets::bind(std::slice::from_ref(addr)).await.is_err(), "{}", addr);
+        }
+        assert!(stream_path.exists() && dgram_path.exists());
+
+        // ...and now they've gone, leaving the files behind.
+        drop((listener, sock));
+        let sockets = Sockets::bind(&[stream, dgram]).await.unwrap();
+        assert_eq!(sockets.endpoints().len(), 2);
+        drop(sockets);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn unix_splice() {
+        let path = std::env::temp_dir().join(format!("ph-splice-{}.sock", std::process::id()));
+        let sockets = Sockets::bind(&[format!("unix://{}", path.display())]).await.unwrap();
+        let opts = Options { engine: Engine::Splice, ..Default::default() };
+        tokio::spawn(serve(sockets, opts, Config::default(), Shutdown::default()));
+
+        let data: Vec<u8> = (0..500_000u32).map(|n| (n % 253) as u8).collect();
+        let (mut r, mut w) = UnixStream::connect(&path).await.unwrap().into_split();
+        let to_send = data.clone();
+        let writer = tokio::spawn(async move {
+            w.write_all(&to_send).await.unwrap();
+            w.shutdown().await.unwrap();
+        });
+        let mut echoed = Vec::new();
+        timeout(Duration::from_secs(10), r.read_to_end(&mut echoed)).await
+            .unwrap().unwrap();
+        writer.await.unwrap();
+        assert_eq!(echoed, data);
+        let _ = std::fs::remove_file(&path);
+    }
+}
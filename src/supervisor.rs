@@ -0,0 +1,194 @@
+/*!
+Running any set of the servers together in one process, each on its own
+port; this is what the `ph` binary does.
+*/
+use std::{future::Future, path::PathBuf, sync::Arc};
+
+use futures::future::select_all;
+use tokio::task::JoinHandle;
+
+use crate::{
+    chat,
+    config::{Config, Section},
+    kvdb, means, mob,
+    net::{bind_udp, Listeners},
+    primetime::{self, MAX_DIGITS},
+    shutdown::{Coordinator, Shutdown, DRAIN_TIMEOUT},
+    smoke,
+};
+
+/// Every server the supervisor knows how to run.
+pub const SECTIONS: &[Section] = &[
+    Section {
+        name: "smoke", about: "Problem 0: Smoke Test (echo, over TCP, UDP or Unix sockets)",
+        opts: smoke::OPTS, bind: "0.0.0.0:12320",
+    },
+    Section {
+        name: "prime", about: "Problem 1: Prime Time",
+        opts: primetime::OPTS, bind: "0.0.0.0:12321",
+    },
+    Section {
+        name: "means", about: "Problem 2: Means to an End",
+        opts: means::OPTS, bind: "0.0.0.0:12322",
+    },
+    Section {
+        name: "chat", about: "Problem 3: Budget Chat",
+        opts: chat::OPTS, bind: "0.0.0.0:12323",
+    },
+    Section {
+        name: "udp", about: "Problem 4: Unusual Database Program",
+        opts: &[], bind: "0.0.0.0:12324",
+    },
+    Section {
+        name: "mob", about: "Problem 5: Mob in the Middle",
+        opts: &[], bind: "0.0.0.0:12325",
+    },
+];
+
+async fn bind(name: &str, cfg: &Config) -> Result<Listeners, String> {
+    let listener = Listeners::bind(&cfg.bind).await?;
+    log::info!("{}: bound to {:?}", name, &listener.local_addrs());
+    Ok(listener)
+}
+
+/// Bind the sockets for the server `name` (one of [`SECTIONS`]) and start
+/// it in its own task, to run until `shutdown`.
+pub async fn launch(
+    name: &str,
+    cfg: Config,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>, String> {
+    let task = match name {
+        "smoke" => {
+            let opts = smoke::Options::from_config(&cfg)?;
+            let sockets = smoke::Sockets::bind(&cfg.bind).await?;
+            log::info!("{}: bound to {:?}", name, &sockets.endpoints());
+            tokio::spawn(smoke::serve(sockets, opts, cfg, shutdown))
+        },
+        "prime" => {
+            let max_digits: usize = cfg.get("max-digits")?.unwrap_or(MAX_DIGITS);
+            if let Some(path) = cfg.get::<PathBuf>("snapshot")? {
+                primetime::warm_start(path);
+            }
+            let methods = Arc::new(primetime::registry(max_digits));
+            let listener = bind(name, &cfg).await?;
+            tokio::spawn(primetime::serve(listener, methods, cfg, shutdown))
+        },
+        "means" => {
+            let opts = means::Options::from_config(&cfg)?;
+            let listener = bind(name, &cfg).await?;
+            tokio::spawn(means::serve(listener, opts, cfg, shutdown))
+        },
+        "chat" => {
+            let opts = chat::Options::from_config(&cfg)?;
+            let listener = bind(name, &cfg).await?;
+            tokio::spawn(chat::serve(listener, opts, cfg, shutdown))
+        },
+        "udp" => {
+            let sock = bind_udp(&cfg.bind).await?;
+            log::info!("{}: listening on {:?}", name, &sock.local_addr());
+            tokio::spawn(kvdb::serve(sock, cfg, shutdown))
+        },
+        "mob" => {
+            let listener = bind(name, &cfg).await?;
+            tokio::spawn(mob::serve(listener, cfg, shutdown))
+        },
+        x => { return Err(format!("no such server {:?}", x)); },
+    };
+    Ok(task)
+}
+
+/**
+Start each of the servers in `configs`, and run them until `stop` (usually
+[`crate::shutdown::signal()`]) finishes. Then shut them all down, giving
+their connections up to the longest of their drain timeouts to finish.
+
+Returns descriptions of any connections that had to be dropped. It's an
+error if any of the servers fails to start (in which case none of them are
+left running), or if one stops before `stop` does, which none ever should.
+*/
+pub async fn run<F: Future>(
+    configs: Vec<(&'static str, Config)>,
+    stop: F,
+) -> Result<Vec<String>, String> {
+    let coordinator = Coordinator::new();
+    let drain = configs.iter()
+        .map(|(_, cfg)| cfg.drain_timeout)
+        .max()
+        .unwrap_or(DRAIN_TIMEOUT);
+
+    let mut names: Vec<&str> = Vec::with_capacity(configs.len());
+    let mut tasks: Vec<JoinHandle<()>> = Vec::with_capacity(configs.len());
+    for (name, cfg) in configs {
+        match launch(name, cfg, coordinator.handle()).await {
+            Ok(task) => {
+                names.push(name);
+                tasks.push(task);
+            },
+            Err(e) => {
+                tasks.iter().for_each(JoinHandle::abort);
+                return Err(format!("{}: {}", name, &e));
+            },
+        }
+    }
+    if tasks.is_empty() {
+        return Err("no servers to run".into());
+    }
+
+    tokio::select! {
+        (res, n, rest) = select_all(tasks) => {
+            coordinator.trigger();
+            rest.iter().for_each(JoinHandle::abort);
+            return match res {
+                Ok(()) => Err(format!("{}: stopped", names[n])),
+                Err(e) => Err(format!("{}: {}", names[n], &e)),
+            };
+        },
+        _ = stop => {},
+    }
+
+    Ok(coordinator.shutdown(drain).await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn local(n: usize) -> Config {
+        let mut cfg = Config::default();
+        cfg.bind = vec!["127.0.0.1:0".to_string(); n];
+        cfg
+    }
+
+    #[tokio::test]
+    async fn launching() {
+        for s in SECTIONS.iter() {
+            launch(s.name, local(1), Shutdown::default()).await.unwrap().abort();
+        }
+
+        assert!(launch("nope", local(1), Shutdown::default()).await.is_err());
+        assert!(launch("udp", local(2), Shutdown::default()).await.is_err());
+        let mut bad = local(1);
+        bad.set("max-digits", "lots");
+        assert!(launch("prime", bad, Shutdown::default()).await.is_err());
+
+        // One failure stops the lot.
+        let mut taken = local(1);
+        let sock = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        taken.bind = vec![sock.local_addr().unwrap().to_string()];
+        let res = run(vec![("smoke", local(1)), ("means", taken)], async {}).await;
+        assert!(res.unwrap_err().starts_with("means: "));
+        assert!(run(Vec::new(), async {}).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stopping() {
+        let configs = SECTIONS.iter()
+            .filter(|s| s.name != "mob")
+            .map(|s| (s.name, local(1)))
+            .collect();
+        let dropped = run(configs, tokio::time::sleep(Duration::from_millis(50))).await;
+        assert_eq!(dropped, Ok(Vec::new()));
+    }
+}
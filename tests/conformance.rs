@@ -0,0 +1,129 @@
+/*!
+Replay the transcripts in `tests/transcripts/` against each server.
+*/
+mod harness;
+
+use std::sync::Arc;
+
+use harness::{run, Server};
+use ph::{chat, config::Config, kvdb, means, mob, primetime, shutdown::Shutdown, smoke};
+
+#[tokio::test]
+async fn smoke() {
+    let server = Server::tcp(|l| smoke::serve(l, Default::default(), Config::default(), Shutdown::default())).await;
+    run(&server, "00_smoke.txt").await;
+}
+
+#[tokio::test]
+async fn prime() {
+    let methods = Arc::new(primetime::registry(primetime::MAX_DIGITS));
+    let server = Server::tcp(|l| primetime::serve(l, methods, Config::default(), Shutdown::default())).await;
+    run(&server, "01_prime.txt").await;
+}
+
+#[tokio::test]
+async fn means() {
+    let server = Server::tcp(|l| means::serve(l, Default::default(), Config::default(), Shutdown::default())).await;
+    run(&server, "02_means.txt").await;
+}
+
+#[tokio::test]
+async fn means_extensions() {
+    let opts = means::Options { extensions: true, ..Default::default() };
+    let server = Server::tcp(|l| means::serve(l, opts, Config::default(), Shutdown::default())).await;
+    run(&server, "02_means_ext.txt").await;
+}
+
+#[tokio::test]
+async fn means_keep_all_nearest() {
+    let opts = means::Options {
+        duplicates: means::Duplicates::All,
+        rounding: means::Rounding::Nearest,
+        ..Default::default()
+    };
+    let server = Server::tcp(|l| means::serve(l, opts, Config::default(), Shutdown::default())).await;
+    run(&server, "02_means_all_nearest.txt").await;
+}
+
+#[tokio::test]
+async fn means_reject_duplicates() {
+    let opts = means::Options { duplicates: means::Duplicates::Reject, ..Default::default() };
+    let server = Server::tcp(|l| means::serve(l, opts, Config::default(), Shutdown::default())).await;
+    run(&server, "02_means_reject.txt").await;
+}
+
+#[tokio::test]
+async fn means_history() {
+    let path = std::env::temp_dir().join(format!("ph-conformance-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let history = means::History::open(&path, Default::default(), Default::default()).unwrap();
+    let opts = means::Options { history: Some(Arc::new(history)), ..Default::default() };
+    let server = Server::tcp(|l| means::serve(l, opts, Config::default(), Shutdown::default())).await;
+    run(&server, "02_means_history.txt").await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn means_budgets() {
+    for (over_budget, transcript) in [
+        (means::OverBudget::Disconnect, "02_means_budget_disconnect.txt"),
+        (means::OverBudget::Reject, "02_means_budget_reject.txt"),
+        (means::OverBudget::Evict, "02_means_budget_evict.txt"),
+    ] {
+        let opts = means::Options {
+            session_memory: Some(2 * means::ENTRY_SIZE),
+            total_memory: Some(3 * means::ENTRY_SIZE),
+            over_budget,
+            ..Default::default()
+        };
+        let server = Server::tcp(|l| means::serve(l, opts, Config::default(), Shutdown::default())).await;
+        run(&server, transcript).await;
+    }
+}
+
+#[tokio::test]
+async fn means_history_budgets() {
+    let path = std::env::temp_dir().join(format!("ph-conformance-budget-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let history = means::History::open(&path, Default::default(), Default::default()).unwrap();
+    let opts = means::Options {
+        history: Some(Arc::new(history)),
+        session_memory: Some(2 * means::ENTRY_SIZE),
+        total_memory: Some(3 * means::ENTRY_SIZE),
+        over_budget: means::OverBudget::Evict,
+        ..Default::default()
+    };
+    let server = Server::tcp(|l| means::serve(l, opts, Config::default(), Shutdown::default())).await;
+    run(&server, "02_means_history_budget.txt").await;
+    // The insert that didn't fit didn't get logged.
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 12);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bchat() {
+    let server = Server::tcp(|l| chat::serve(l, Default::default(), Config::default(), Shutdown::default())).await;
+    run(&server, "03_bchat.txt").await;
+}
+
+#[tokio::test]
+async fn bchat_commands() {
+    let opts = chat::Options { commands: true };
+    let server = Server::tcp(|l| chat::serve(l, opts, Config::default(), Shutdown::default())).await;
+    run(&server, "03_bchat_commands.txt").await;
+}
+
+#[tokio::test]
+async fn udp() {
+    let server = Server::udp(|s| kvdb::serve(s, Config::default(), Shutdown::default())).await;
+    run(&server, "04_udp.txt").await;
+}
+
+#[tokio::test]
+async fn mob() {
+    let chat = Server::tcp(|l| chat::serve(l, Default::default(), Config::default(), Shutdown::default())).await;
+    let mut cfg = Config::default();
+    cfg.upstream = Some(chat.addr.to_string());
+    let server = Server::tcp(|l| mob::serve(l, cfg, Shutdown::default())).await;
+    run(&server, "05_mob.txt").await;
+}
@@ -0,0 +1,340 @@
+/*!
+Transcript-driven conformance testing for the Protohackers servers.
+
+A transcript is a text file (in `tests/transcripts/`) describing a
+conversation between one or more clients and a server, one step per line:
+
+```text
+# Comments and blank lines are ignored.
+@alice                          open a connection called "alice"
+alice > some text\n             send bytes (escapes: \n \r \t \\ \xHH)
+alice < some text\n             expect exactly these bytes next
+alice >x 49 00 00 30 39         send bytes written in hex
+alice <x 00 00 00 65            expect bytes written in hex
+alice >file 01_prime.json       send the contents of a file in tests/
+alice <file 01_prime.expected   expect the contents of a file in tests/
+alice shut                      shut down our write half of the connection
+alice closed                    expect the server to close the connection
+alice silent 200                expect nothing to arrive for 200 ms
+```
+
+Over UDP each send is a single datagram, and each expectation is that the
+next datagram to arrive is exactly what's given. `shut` and `closed` don't
+mean anything there.
+
+When an expectation isn't met, the test panics with the transcript line,
+what was expected, what actually arrived, and where they first differ.
+*/
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+    time::timeout,
+};
+
+/// How long to wait for expected bytes before giving up.
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// A server under test, running in its own task on a free local port. The
+/// task gets aborted when this is dropped.
+pub struct Server {
+    task: JoinHandle<()>,
+    pub addr: SocketAddr,
+    pub transport: Transport,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Server {
+    /// Bind a TCP listener to a free local port and hand it to `serve`.
+    pub async fn tcp<F, Fut>(serve: F) -> Server
+    where
+        F: FnOnce(TcpListener) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(serve(listener));
+        Server { task, addr, transport: Transport::Tcp }
+    }
+
+    /// Bind a UDP socket to a free local port and hand it to `serve`.
+    pub async fn udp<F, Fut>(serve: F) -> Server
+    where
+        F: FnOnce(UdpSocket) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let task = tokio::spawn(serve(sock));
+        Server { task, addr, transport: Transport::Udp }
+    }
+}
+
+#[derive(Debug)]
+enum Op {
+    Open,
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+    Shut,
+    Closed,
+    Silent(Duration),
+}
+
+#[derive(Debug)]
+struct Step {
+    line: usize,
+    conn: String,
+    op: Op,
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join(name)
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'n') => out.push(b'\n'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).map_err(|e| e.to_string())?;
+                out.push(u8::from_str_radix(hex, 16).map_err(|e| format!(
+                    "bad escape \\x{}: {}", hex, &e
+                ))?);
+            },
+            x => { return Err(format!("bad escape: \\{:?}", x.map(char::from))); },
+        }
+    }
+    Ok(out)
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    text.split_whitespace()
+        .map(|h| u8::from_str_radix(h, 16).map_err(|e| format!("bad hex {:?}: {}", h, &e)))
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<Option<(String, Op)>, String> {
+    let trimmed = line.trim_start();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(None);
+    }
+    if let Some(name) = trimmed.strip_prefix('@') {
+        return Ok(Some((name.trim().to_string(), Op::Open)));
+    }
+
+    let (conn, rest) = trimmed.split_once(' ')
+        .ok_or_else(|| format!("no operation: {:?}", line))?;
+    let (op, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+    let op = match op {
+        ">" => Op::Send(unescape(arg)?),
+        "<" => Op::Expect(unescape(arg)?),
+        ">x" => Op::Send(unhex(arg)?),
+        "<x" => Op::Expect(unhex(arg)?),
+        ">file" | "<file" => {
+            let path = fixture_path(arg.trim());
+            let bytes = std::fs::read(&path).map_err(|e| format!(
+                "unable to read {}: {}", path.display(), &e
+            ))?;
+            if op == ">file" { Op::Send(bytes) } else { Op::Expect(bytes) }
+        },
+        "shut" => Op::Shut,
+        "closed" => Op::Closed,
+        "silent" => {
+            let ms: u64 = arg.trim().parse().map_err(|e| format!(
+                "bad duration {:?}: {}", arg, &e
+            ))?;
+            Op::Silent(Duration::from_millis(ms))
+        },
+        x => { return Err(format!("unknown operation {:?}", x)); },
+    };
+
+    Ok(Some((conn.to_string(), op)))
+}
+
+fn parse(name: &str) -> Vec<Step> {
+    let path = fixture_path(&format!("transcripts/{}", name));
+    let text = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("unable to read {}: {}", path.display(), &e));
+
+    let mut steps = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some((conn, op))) => steps.push(Step { line: n + 1, conn, op }),
+            Ok(None) => {},
+            Err(e) => panic!("{}:{}: {}", name, n + 1, &e),
+        }
+    }
+    steps
+}
+
+enum Conn {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Conn {
+    async fn open(server: &Server) -> std::io::Result<Conn> {
+        match server.transport {
+            Transport::Tcp => Ok(Conn::Tcp(TcpStream::connect(server.addr).await?)),
+            Transport::Udp => {
+                let sock = UdpSocket::bind("127.0.0.1:0").await?;
+                sock.connect(server.addr).await?;
+                Ok(Conn::Udp(sock))
+            },
+        }
+    }
+
+    async fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Conn::Tcp(sock) => sock.write_all(bytes).await,
+            Conn::Udp(sock) => sock.send(bytes).await.map(|_| ()),
+        }
+    }
+
+    /// Read what should be `n` bytes: whatever arrives before `n` bytes
+    /// have, the connection closes, or `patience` runs out.
+    async fn receive(&mut self, n: usize, patience: Duration) -> (Vec<u8>, Outcome) {
+        match self {
+            Conn::Tcp(sock) => {
+                let mut got = Vec::with_capacity(n);
+                let res = timeout(patience, async {
+                    let mut buff = vec![0u8; n.max(1)];
+                    while got.len() < n.max(1) {
+                        let want = n.max(1) - got.len();
+                        match sock.read(&mut buff[..want]).await {
+                            Ok(0) => { return Outcome::Closed; },
+                            Ok(k) => { got.extend_from_slice(&buff[..k]); },
+                            Err(e) => { return Outcome::Error(e.to_string()); },
+                        }
+                    }
+                    Outcome::Data
+                }).await;
+                (got, res.unwrap_or(Outcome::TimedOut))
+            },
+            Conn::Udp(sock) => {
+                let mut buff = vec![0u8; 65536];
+                match timeout(patience, sock.recv(&mut buff)).await {
+                    Ok(Ok(k)) => (buff[..k].to_vec(), Outcome::Data),
+                    Ok(Err(e)) => (Vec::new(), Outcome::Error(e.to_string())),
+                    Err(_) => (Vec::new(), Outcome::TimedOut),
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Data,
+    Closed,
+    TimedOut,
+    Error(String),
+}
+
+fn show(bytes: &[u8]) -> String {
+    bytes.escape_ascii().to_string()
+}
+
+fn diff(expected: &[u8], got: &[u8], outcome: &Outcome) -> String {
+    let at = expected.iter().zip(got.iter())
+        .position(|(a, b)| a != b)
+        .unwrap_or(expected.len().min(got.len()));
+    format!(
+        "expected: \"{}\"\n     got: \"{}\" ({:?})\nfirst difference at byte {}",
+        show(expected), show(got), outcome, at
+    )
+}
+
+/// Replay the transcript `name` against `server`, panicking with a report
+/// at the first step that doesn't go as expected.
+pub async fn run(server: &Server, name: &str) {
+    let mut conns: HashMap<String, Conn> = HashMap::new();
+
+    for step in parse(name) {
+        let here = format!("{}:{} ({})", name, step.line, &step.conn);
+        if let Op::Open = step.op {
+            let conn = Conn::open(server).await
+                .unwrap_or_else(|e| panic!("{}: unable to connect: {}", &here, &e));
+            conns.insert(step.conn, conn);
+            continue;
+        }
+        let conn = conns.get_mut(&step.conn)
+            .unwrap_or_else(|| panic!("{}: no such connection", &here));
+
+        match step.op {
+            Op::Open => unreachable!(),
+            Op::Send(bytes) => {
+                conn.send(&bytes).await
+                    .unwrap_or_else(|e| panic!("{}: error sending: {}", &here, &e));
+            },
+            Op::Expect(bytes) => {
+                let (got, outcome) = conn.receive(bytes.len(), EXPECT_TIMEOUT).await;
+                if got != bytes {
+                    panic!("{}: unexpected response\n{}", &here, diff(&bytes, &got, &outcome));
+                }
+            },
+            Op::Shut => match conn {
+                Conn::Tcp(sock) => {
+                    sock.shutdown().await
+                        .unwrap_or_else(|e| panic!("{}: error shutting down: {}", &here, &e));
+                },
+                Conn::Udp(_) => panic!("{}: can't shut a UDP socket", &here),
+            },
+            Op::Closed => {
+                let (got, outcome) = conn.receive(1, EXPECT_TIMEOUT).await;
+                let closed = match &outcome {
+                    Outcome::Closed => true,
+                    // A reset is as good as a close.
+                    Outcome::Error(_) => got.is_empty(),
+                    _ => false,
+                };
+                if !closed {
+                    panic!(
+                        "{}: expected the connection to close\n     got: \"{}\" ({:?})",
+                        &here, show(&got), &outcome
+                    );
+                }
+            },
+            Op::Silent(patience) => {
+                let (got, outcome) = conn.receive(1, patience).await;
+                if outcome != Outcome::TimedOut || !got.is_empty() {
+                    panic!(
+                        "{}: expected silence\n     got: \"{}\" ({:?})",
+                        &here, show(&got), &outcome
+                    );
+                }
+            },
+        }
+    }
+}
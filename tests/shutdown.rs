@@ -0,0 +1,136 @@
+/*!
+Shutting servers down while clients are connected.
+*/
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use ph::{
+    chat,
+    config::Config,
+    means, mob, primetime,
+    shutdown::{Coordinator, Shutdown},
+    smoke,
+};
+
+const PATIENCE: Duration = Duration::from_secs(5);
+
+async fn listen() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+struct Client(BufReader<TcpStream>);
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> Client {
+        Client(BufReader::new(TcpStream::connect(addr).await.unwrap()))
+    }
+
+    async fn send(&mut self, text: &str) {
+        self.0.get_mut().write_all(text.as_bytes()).await.unwrap();
+    }
+
+    async fn line(&mut self) -> String {
+        let mut line = String::new();
+        timeout(PATIENCE, self.0.read_line(&mut line)).await.unwrap().unwrap();
+        line
+    }
+
+    async fn closed(&mut self) -> bool {
+        let mut rest = Vec::new();
+        matches!(timeout(PATIENCE, self.0.read_to_end(&mut rest)).await, Ok(Ok(0)))
+    }
+}
+
+#[tokio::test]
+async fn chat_says_goodbye() {
+    let coordinator = Coordinator::new();
+    let (listener, addr) = listen().await;
+    let server = tokio::spawn(chat::serve(listener, Default::default(), Config::default(), coordinator.handle()));
+
+    let mut alice = Client::connect(addr).await;
+    alice.line().await;
+    alice.send("alice\n").await;
+    assert_eq!(alice.line().await, "* Also here: \n");
+    // Bob never gets as far as giving his name.
+    let mut bob = Client::connect(addr).await;
+    bob.line().await;
+    assert_eq!(coordinator.live().len(), 2);
+
+    assert!(coordinator.shutdown(PATIENCE).await.is_empty());
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(client.line().await, "* The server is shutting down. Goodbye.\n");
+        assert!(client.closed().await);
+    }
+
+    timeout(PATIENCE, server).await.unwrap().unwrap();
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn mob_closes_both_sides() {
+    let (listener, chat_addr) = listen().await;
+    tokio::spawn(chat::serve(listener, Default::default(), Config::default(), Shutdown::default()));
+
+    let coordinator = Coordinator::new();
+    let (listener, addr) = listen().await;
+    let mut cfg = Config::default();
+    cfg.upstream = Some(chat_addr.to_string());
+    tokio::spawn(mob::serve(listener, cfg, coordinator.handle()));
+
+    let mut watcher = Client::connect(chat_addr).await;
+    watcher.line().await;
+    watcher.send("watcher\n").await;
+    watcher.line().await;
+
+    let mut alice = Client::connect(addr).await;
+    alice.line().await;
+    alice.send("alice\n").await;
+    assert_eq!(alice.line().await, "* Also here: watcher\n");
+    assert_eq!(watcher.line().await, "* alice joins.\n");
+
+    assert!(coordinator.shutdown(PATIENCE).await.is_empty());
+    assert!(alice.closed().await);
+    assert_eq!(watcher.line().await, "* alice leaves.\n");
+}
+
+#[tokio::test]
+async fn others_hang_up() {
+    let coordinator = Coordinator::new();
+    let mut addrs = Vec::new();
+
+    let (listener, addr) = listen().await;
+    tokio::spawn(smoke::serve(listener, Default::default(), Config::default(), coordinator.handle()));
+    addrs.push(addr);
+    let (listener, addr) = listen().await;
+    tokio::spawn(means::serve(listener, Default::default(), Config::default(), coordinator.handle()));
+    addrs.push(addr);
+    let (listener, addr) = listen().await;
+    let methods = Arc::new(primetime::registry(primetime::MAX_DIGITS));
+    tokio::spawn(primetime::serve(listener, methods, Config::default(), coordinator.handle()));
+    addrs.push(addr);
+
+    let mut clients = Vec::new();
+    for &addr in addrs.iter() {
+        clients.push(Client::connect(addr).await);
+    }
+    // Make sure they've all been accepted.
+    clients[0].send("hi\n").await;
+    assert_eq!(clients[0].line().await, "hi\n");
+    clients[2].send("{\"method\":\"isPrime\",\"number\":7}\n").await;
+    assert_eq!(clients[2].line().await, "{\"method\":\"isPrime\",\"prime\":true}\n");
+    while coordinator.live().len() < 3 {
+        tokio::task::yield_now().await;
+    }
+
+    assert!(coordinator.shutdown(PATIENCE).await.is_empty());
+    for client in clients.iter_mut() {
+        assert!(client.closed().await);
+    }
+}